@@ -4,4 +4,20 @@
 # if hasattr(maptide, "__all__"):
 #     __all__ = maptide.__all__
 
-from .api import query, parse_region
+from .maptide import Pileup, PileupOptions  #  type: ignore
+from .api import (
+    query,
+    pileup,
+    pileup_groups,
+    query_iter,
+    query_arrow,
+    query_parquet,
+    query_tsv,
+    query_bedgraph,
+    query_depth,
+    query_mpileup,
+    query_summary,
+    query_histogram,
+    merge,
+    parse_region,
+)
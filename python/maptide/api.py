@@ -1,6 +1,7 @@
 import os
-from typing import Dict, Tuple, Optional, Any
+from typing import Dict, Tuple, Optional, Any, Iterable, Iterator, List
 from . import maptide  #  type: ignore
+from .maptide import Pileup, PileupOptions  #  type: ignore
 
 
 BASES = ["A", "C", "G", "T", "DS", "N"]
@@ -10,9 +11,9 @@ def query(
     bam: str,
     region: Optional[str] = None,
     bai: Optional[str] = None,
-    mapping_quality: int = 0,
-    base_quality: int = 0,
     annotated: bool = False,
+    options: Optional[PileupOptions] = None,
+    **kwargs: Any,
 ) -> Dict[str, Dict[Tuple[int, int], Any]]:
     """Performs a pileup over a region, obtaining per-position base frequencies for the provided BAM file.
 
@@ -24,25 +25,20 @@ def query(
         Region to query, in the form `CHROM:START-END` (default: all positions)
     bai : str, optional
         Path to index file (default: same path as the BAM file, but with .bai appended)
-    mapping_quality : int, optional
-        Minimum mapping quality for a read to be included in the pileup (default: 0)
-    base_quality : int, optional
-        Minimum base quality for a base within a read to be included in the pileup (default: 0)
     annotated : bool, optional
         Return frequencies annotated with their bases, as a `dict[str, int]`. Default is to return frequencies only, as a `list[int]` (default: False)
+    options : PileupOptions, optional
+        Options for the pileup (default: built from `kwargs`)
+    **kwargs
+        Options for the pileup, passed to `PileupOptions` if `options` is not given
 
     Returns
     -------
     dict
-        Mapping: reference -> (reference position, insert position) -> [base frequencies].
+        Mapping: reference -> (reference position, insert position) -> [base frequencies], with positions in sorted order.
     """
 
-    if region:
-        if not bai and os.path.isfile(bam + ".bai"):
-            bai = bam + ".bai"
-        data = maptide.query(bam, bai, region, mapping_quality, base_quality)
-    else:
-        data = maptide.all(bam, mapping_quality, base_quality)
+    data = pileup(bam, region=region, bai=bai, options=options, **kwargs).to_dict()
 
     if annotated:
         for _, positions in data.items():
@@ -52,6 +48,460 @@ def query(
     return data
 
 
+def pileup(
+    bam: str,
+    region: Optional[str] = None,
+    bai: Optional[str] = None,
+    options: Optional[PileupOptions] = None,
+    **kwargs: Any,
+) -> Pileup:
+    """Performs a pileup over a region, returning a `Pileup` that can be queried without converting every position into Python objects.
+
+    Parameters
+    ----------
+    bam : str
+        Path to the BAM file.
+    region : str, optional
+        Region to query, in the form `CHROM:START-END` (default: all positions)
+    bai : str, optional
+        Path to index file (default: same path as the BAM file, but with .bai appended)
+    options : PileupOptions, optional
+        Options for the pileup (default: built from `kwargs`)
+    **kwargs
+        Options for the pileup, passed to `PileupOptions` if `options` is not given
+
+    Returns
+    -------
+    Pileup
+        Counts at each reference position and insertion, with methods `depth`, `frequencies`, `insertions_at`, `slice` and `to_dict`. Iterating over it yields (reference, reference position, insert position, [base frequencies]) in sorted order.
+    """
+
+    bai = _find_index(bam, bai)
+
+    options = _options(options, **kwargs)
+
+    if region:
+        return maptide.query(bam, bai, region, options)
+    else:
+        return maptide.all(bam, bai, options)
+
+
+def pileup_groups(
+    bam: str,
+    region: Optional[str] = None,
+    bai: Optional[str] = None,
+    group_by: str = "read_group",
+    options: Optional[PileupOptions] = None,
+    **kwargs: Any,
+) -> Dict[str, Pileup]:
+    """Performs a pileup over a region, returning a separate `Pileup` for each read group or sample, counted from a single pass over the BAM file.
+
+    Parameters
+    ----------
+    bam : str
+        Path to the BAM file.
+    region : str, optional
+        Region to query, in the form `CHROM:START-END` (default: all positions)
+    bai : str, optional
+        Path to index file (default: same path as the BAM file, but with .bai appended)
+    group_by : str, optional
+        How reads are grouped, one of `read_group` (the `RG` tag of each read) or `sample` (the `SM` field of the read group in the header) (default: `read_group`)
+    options : PileupOptions, optional
+        Options for the pileup (default: built from `kwargs`)
+    **kwargs
+        Options for the pileup, passed to `PileupOptions` if `options` is not given
+
+    Returns
+    -------
+    dict
+        Pileup of each group. Every read group in the header has a pileup, even without any reads. Reads without a read group (or whose read group has no sample) are counted in the group `*`.
+    """
+
+    bai = _find_index(bam, bai)
+
+    options = _options(options, **kwargs)
+
+    return maptide.groups(bam, bai, region, group_by, options)
+
+
+def query_iter(
+    bam: str,
+    region: Optional[str] = None,
+    bai: Optional[str] = None,
+    options: Optional[PileupOptions] = None,
+    **kwargs: Any,
+) -> Iterator[Tuple[str, int, int, List[int]]]:
+    """Performs a pileup over a region, lazily yielding the per-position base frequencies in sorted order.
+
+    Rows are yielded as soon as no further reads can change them, so memory use does not grow with the size of the reference. The BAM file must be sorted by coordinate.
+
+    Parameters
+    ----------
+    bam : str
+        Path to the BAM file.
+    region : str, optional
+        Region to query, in the form `CHROM:START-END` (default: all positions)
+    bai : str, optional
+        Path to index file (default: same path as the BAM file, but with .bai appended)
+    options : PileupOptions, optional
+        Options for the pileup (default: built from `kwargs`)
+    **kwargs
+        Options for the pileup, passed to `PileupOptions` if `options` is not given
+
+    Returns
+    -------
+    iterator
+        Iterator of (reference, reference position, insert position, [base frequencies]).
+    """
+
+    options = _options(options, **kwargs)
+
+    if region:
+        bai = _find_index(bam, bai)
+        return maptide.query_iter(bam, bai, region, options)
+    else:
+        return maptide.all_iter(bam, options)
+
+
+def query_arrow(
+    bam: str,
+    region: Optional[str] = None,
+    bai: Optional[str] = None,
+    options: Optional[PileupOptions] = None,
+    **kwargs: Any,
+) -> Any:
+    """Performs a pileup over a region, returning the per-position base frequencies as a `pyarrow.RecordBatch`.
+
+    Requires `pyarrow` to be installed.
+
+    Parameters
+    ----------
+    bam : str
+        Path to the BAM file.
+    region : str, optional
+        Region to query, in the form `CHROM:START-END` (default: all positions)
+    bai : str, optional
+        Path to index file (default: same path as the BAM file, but with .bai appended)
+    options : PileupOptions, optional
+        Options for the pileup (default: built from `kwargs`)
+    **kwargs
+        Options for the pileup, passed to `PileupOptions` if `options` is not given
+
+    Returns
+    -------
+    pyarrow.RecordBatch
+        Table with columns `chrom`, `pos`, `ins`, `cov`, `a`, `c`, `g`, `t`, `ds`, `n`, sorted by coordinate.
+    """
+
+    bai = _find_index(bam, bai)
+
+    options = _options(options, **kwargs)
+
+    if region:
+        return maptide.query_arrow(bam, bai, region, options)
+    else:
+        return maptide.all_arrow(bam, bai, options)
+
+
+def query_parquet(
+    bam: str,
+    path: str,
+    region: Optional[str] = None,
+    bai: Optional[str] = None,
+    options: Optional[PileupOptions] = None,
+    **kwargs: Any,
+) -> None:
+    """Performs a pileup over a region, writing the per-position base frequencies to a Parquet file.
+
+    Parameters
+    ----------
+    bam : str
+        Path to the BAM file.
+    path : str
+        Path to the output Parquet file.
+    region : str, optional
+        Region to query, in the form `CHROM:START-END` (default: all positions)
+    bai : str, optional
+        Path to index file (default: same path as the BAM file, but with .bai appended)
+    options : PileupOptions, optional
+        Options for the pileup (default: built from `kwargs`)
+    **kwargs
+        Options for the pileup, passed to `PileupOptions` if `options` is not given
+    """
+
+    bai = _find_index(bam, bai)
+
+    options = _options(options, **kwargs)
+
+    if region:
+        maptide.query_parquet(bam, bai, region, path, options)
+    else:
+        maptide.all_parquet(bam, bai, path, options)
+
+
+def query_tsv(
+    bam: str,
+    path: Optional[str] = None,
+    region: Optional[str] = None,
+    bai: Optional[str] = None,
+    options: Optional[PileupOptions] = None,
+    **kwargs: Any,
+) -> None:
+    """Performs a pileup over a region, writing the per-position base frequencies as a tab-separated table.
+
+    Lines are written as soon as no further reads can change them. The BAM file must be sorted by coordinate. Per-position statistics are written with `stats=True`, and the output is compressed with `bgzip=True` (see `PileupOptions`).
+
+    Parameters
+    ----------
+    bam : str
+        Path to the BAM file.
+    path : str, optional
+        Path to the output file (default: stdout)
+    region : str, optional
+        Region to query, in the form `CHROM:START-END` (default: all positions)
+    bai : str, optional
+        Path to index file (default: same path as the BAM file, but with .bai appended)
+    options : PileupOptions, optional
+        Options for the pileup (default: built from `kwargs`)
+    **kwargs
+        Options for the pileup, passed to `PileupOptions` if `options` is not given
+    """
+
+    # The statistics need the qualities at each position
+    if options is None:
+        kwargs.setdefault("qualities", kwargs.get("stats", False))
+    options = _options(options, **kwargs)
+
+    if region:
+        bai = _find_index(bam, bai)
+        maptide.query_tsv(bam, bai, region, path, options)
+    else:
+        maptide.all_tsv(bam, path, options)
+
+
+def query_bedgraph(
+    bam: str,
+    path: Optional[str] = None,
+    region: Optional[str] = None,
+    bai: Optional[str] = None,
+    base: Optional[str] = None,
+    options: Optional[PileupOptions] = None,
+    **kwargs: Any,
+) -> None:
+    """Performs a pileup over a region, writing the per-position depth as a bedGraph track.
+
+    Consecutive positions with the same depth are merged into a single interval.
+
+    Parameters
+    ----------
+    bam : str
+        Path to the BAM file.
+    path : str, optional
+        Path to the output file (default: stdout)
+    region : str, optional
+        Region to query, in the form `CHROM:START-END` (default: all positions)
+    bai : str, optional
+        Path to index file (default: same path as the BAM file, but with .bai appended)
+    base : str, optional
+        Report the depth of one of A,C,G,T,DS,N, instead of the total depth (default: None)
+    options : PileupOptions, optional
+        Options for the pileup (default: built from `kwargs`)
+    **kwargs
+        Options for the pileup, passed to `PileupOptions` if `options` is not given
+    """
+
+    bai = _find_index(bam, bai)
+
+    options = _options(options, **kwargs)
+
+    if region:
+        maptide.query_bedgraph(bam, bai, region, path, base, options)
+    else:
+        maptide.all_bedgraph(bam, bai, path, base, options)
+
+
+def query_depth(
+    bam: str,
+    path: Optional[str] = None,
+    region: Optional[str] = None,
+    bai: Optional[str] = None,
+    base: Optional[str] = None,
+    options: Optional[PileupOptions] = None,
+    **kwargs: Any,
+) -> None:
+    """Performs a pileup over a region, writing the per-position depth in the format of `samtools depth -a`.
+
+    Parameters
+    ----------
+    bam : str
+        Path to the BAM file.
+    path : str, optional
+        Path to the output file (default: stdout)
+    region : str, optional
+        Region to query, in the form `CHROM:START-END` (default: all positions)
+    bai : str, optional
+        Path to index file (default: same path as the BAM file, but with .bai appended)
+    base : str, optional
+        Report the depth of one of A,C,G,T,DS,N, instead of the total depth (default: None)
+    options : PileupOptions, optional
+        Options for the pileup (default: built from `kwargs`)
+    **kwargs
+        Options for the pileup, passed to `PileupOptions` if `options` is not given
+    """
+
+    bai = _find_index(bam, bai)
+
+    options = _options(options, **kwargs)
+
+    if region:
+        maptide.query_depth(bam, bai, region, path, base, options)
+    else:
+        maptide.all_depth(bam, bai, path, base, options)
+
+
+def query_mpileup(
+    bam: str,
+    path: Optional[str] = None,
+    region: Optional[str] = None,
+    bai: Optional[str] = None,
+    reference: Optional[str] = None,
+    options: Optional[PileupOptions] = None,
+    **kwargs: Any,
+) -> None:
+    """Performs a pileup over a region, writing the reads covering each position in the format of `samtools mpileup`.
+
+    The BAM file must be sorted by coordinate.
+
+    Parameters
+    ----------
+    bam : str
+        Path to the BAM file.
+    path : str, optional
+        Path to the output file (default: stdout)
+    region : str, optional
+        Region to query, in the form `CHROM:START-END` (default: all positions)
+    bai : str, optional
+        Path to index file (default: same path as the BAM file, but with .bai appended)
+    reference : str, optional
+        Path to the reference FASTA file. Without it, the reference base is written as N (default: None)
+    options : PileupOptions, optional
+        Options for the pileup (default: built from `kwargs`)
+    **kwargs
+        Options for the pileup, passed to `PileupOptions` if `options` is not given
+    """
+
+    options = _options(options, **kwargs)
+
+    if region:
+        bai = _find_index(bam, bai)
+        maptide.query_mpileup(bam, bai, region, path, reference, options)
+    else:
+        maptide.all_mpileup(bam, path, reference, options)
+
+
+def query_summary(
+    bam: str,
+    path: Optional[str] = None,
+    region: Optional[str] = None,
+    bai: Optional[str] = None,
+    decimals: int = 3,
+    options: Optional[PileupOptions] = None,
+    **kwargs: Any,
+) -> None:
+    """Performs a pileup over a region, writing a table of the coverage of each reference (or of the region).
+
+    The table has columns `chrom`, `start`, `end`, `mean_depth`, `median_depth`, `breadth_1x`, `breadth_10x`, `breadth_20x`, `n_fraction` and `deletion_fraction`.
+
+    Parameters
+    ----------
+    bam : str
+        Path to the BAM file.
+    path : str, optional
+        Path to the output file (default: stdout)
+    region : str, optional
+        Region to query, in the form `CHROM:START-END` (default: all positions)
+    bai : str, optional
+        Path to index file (default: same path as the BAM file, but with .bai appended)
+    decimals : int, optional
+        Number of decimal places to display for the metrics (default: 3)
+    options : PileupOptions, optional
+        Options for the pileup (default: built from `kwargs`)
+    **kwargs
+        Options for the pileup, passed to `PileupOptions` if `options` is not given
+    """
+
+    data = pileup(bam, region=region, bai=bai, options=options, **kwargs)
+    data.write_summary(path, decimals)
+
+
+def query_histogram(
+    bam: str,
+    path: Optional[str] = None,
+    region: Optional[str] = None,
+    bai: Optional[str] = None,
+    cap: int = 1000,
+    options: Optional[PileupOptions] = None,
+    **kwargs: Any,
+) -> None:
+    """Performs a pileup over a region, writing a histogram of the depth of each reference (or of the region).
+
+    The table has columns `chrom`, `depth` and `positions`, with a line for each depth from zero up to `cap`.
+
+    Parameters
+    ----------
+    bam : str
+        Path to the BAM file.
+    path : str, optional
+        Path to the output file (default: stdout)
+    region : str, optional
+        Region to query, in the form `CHROM:START-END` (default: all positions)
+    bai : str, optional
+        Path to index file (default: same path as the BAM file, but with .bai appended)
+    cap : int, optional
+        Depth of the final bucket, which also counts all positions with a greater depth (default: 1000)
+    options : PileupOptions, optional
+        Options for the pileup (default: built from `kwargs`)
+    **kwargs
+        Options for the pileup, passed to `PileupOptions` if `options` is not given
+    """
+
+    data = pileup(bam, region=region, bai=bai, options=options, **kwargs)
+    data.write_histogram(path, cap)
+
+
+def merge(pileups: Iterable[Pileup]) -> Pileup:
+    """Merges pileups of the same sample, such as those from separate lanes or flowcells, by adding their counts position by position.
+
+    Parameters
+    ----------
+    pileups : iterable of Pileup
+        Pileups to merge, of which there must be at least one (a `ValueError` is raised otherwise). These must have the same references, with the same lengths and counted positions.
+
+    Returns
+    -------
+    Pileup
+        Merged pileup, with the options of the first pileup.
+    """
+    return maptide.merge(list(pileups))
+
+
+def _options(options: Optional[PileupOptions], **kwargs: Any) -> PileupOptions:
+    if options is None:
+        return PileupOptions(**kwargs)
+    if kwargs:
+        raise TypeError(
+            "pileup options cannot be given alongside `options`: "
+            + ", ".join(sorted(kwargs))
+        )
+    return options
+
+
+def _find_index(bam: str, bai: Optional[str]) -> Optional[str]:
+    if not bai and os.path.isfile(bam + ".bai"):
+        bai = bam + ".bai"
+    return bai
+
+
 def parse_region(region: str) -> Tuple[str, int, int]:
     """Parses a region of the form `CHROM:START-END`, returning the tuple `(CHROM, START, END)`.
 
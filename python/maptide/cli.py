@@ -1,58 +1,8 @@
 import argparse
-import math
-import sys
-import csv
 import pkg_resources
 from . import api
 
 
-def entropy(probabilities, normalised=False):
-    ent = sum([-(x * math.log2(x)) if x != 0 else 0 for x in probabilities])
-
-    if normalised:
-        return ent / math.log2(len(probabilities))
-    else:
-        return ent
-
-
-def get_stats(counts, decimals=3):
-    coverage = sum(counts)
-    probabilities = [count / coverage if coverage > 0 else 0.0 for count in counts]
-    percentages = [100 * probability for probability in probabilities]
-    ent = entropy(probabilities, normalised=True)
-    secondary_count = list(counts)
-    secondary_count.pop(counts.index(max(counts)))
-    secondary_coverage = sum(secondary_count)
-    secondary_probabilities = [
-        count / secondary_coverage if secondary_coverage > 0 else 0.0
-        for count in secondary_count
-    ]
-    secondary_ent = entropy(secondary_probabilities, normalised=True)
-    return (
-        [coverage]
-        + counts
-        + [round(x, decimals) for x in percentages + [ent, secondary_ent]]
-    )
-
-
-def iterate(data, region=None, stats=False, decimals=3):
-    if region:
-        chrom, start, end = api.parse_region(region)
-        for (pos, ins_pos), row in sorted(data[chrom].items()):
-            if (not start or pos >= start) and (not end or pos <= end):
-                if stats:
-                    yield [chrom, pos, ins_pos] + get_stats(row, decimals=decimals)
-                else:
-                    yield [chrom, pos, ins_pos, sum(row)] + row
-    else:
-        for chrom, chrom_data in data.items():
-            for (pos, ins_pos), row in sorted(chrom_data.items()):
-                if stats:
-                    yield [chrom, pos, ins_pos] + get_stats(row, decimals=decimals)
-                else:
-                    yield [chrom, pos, ins_pos, sum(row)] + row
-
-
 def run():
     parser = argparse.ArgumentParser()
     parser.add_argument("bam", help="Path to BAM file")
@@ -86,12 +36,49 @@ def run():
         default=0,
         help="Minimum base quality (default: %(default)s)",
     )
+    parser.add_argument(
+        "--trim-ends",
+        type=int,
+        default=0,
+        help="Number of bases to ignore at each end of the aligned part of a read, after any soft clips (default: %(default)s)",
+    )
+    parser.add_argument(
+        "--read-groups",
+        nargs="+",
+        help="Read groups to include, from the RG tag of each read (default: all reads)",
+    )
+    parser.add_argument(
+        "--tag-filter",
+        action="append",
+        dest="tag_filters",
+        metavar="EXPRESSION",
+        help="Filter on the tags of each read, which may be given more than once: TAG, !TAG, TAG>N (or ==, !=, <, <=, >=), or TAG in A,B (or !in, with @FILE for one value per line) (default: no filters)",
+    )
+    parser.add_argument(
+        "--overlaps",
+        choices=["count", "quality", "consensus"],
+        default="count",
+        help="How the bases of a pair of mates are counted where the mates overlap (mpileup output only supports count): both, the higher quality base, or once if they agree and as an N if not (default: %(default)s)",
+    )
+    parser.add_argument(
+        "-t",
+        "--threads",
+        type=int,
+        default=1,
+        help="Number of threads used to read the BAM file (default: %(default)s)",
+    )
     parser.add_argument(
         "-s",
         "--stats",
         action="store_true",
         default=False,
-        help="Output additional per-position statistics (default: %(default)s)",
+        help="Output additional per-position statistics, including base and mapping qualities (default: %(default)s)",
+    )
+    parser.add_argument(
+        "--end-distance",
+        type=int,
+        default=10,
+        help="For stats output, bases within this distance of the nearer aligned end of their read are counted as near the end (default: %(default)s)",
     )
     parser.add_argument(
         "-d",
@@ -100,37 +87,181 @@ def run():
         default=3,
         help="Number of decimal places to display (default: %(default)s)",
     )
+    parser.add_argument(
+        "--summary",
+        action="store_true",
+        default=False,
+        help="Print a table of the coverage of each reference (or of the region) instead of the pileup (default: %(default)s)",
+    )
+    parser.add_argument(
+        "--histogram",
+        action="store_true",
+        default=False,
+        help="Print a histogram of the depth of each reference (or of the region) instead of the pileup (default: %(default)s)",
+    )
+    parser.add_argument(
+        "--histogram-cap",
+        type=int,
+        default=1000,
+        help="For histogram output, depth of the final bucket, which also counts all greater depths (default: %(default)s)",
+    )
+    parser.add_argument(
+        "-f",
+        "--format",
+        choices=["tsv", "bedgraph", "depth", "mpileup"],
+        default="tsv",
+        help="Output format (default: %(default)s)",
+    )
+    parser.add_argument(
+        "--base",
+        choices=api.BASES,
+        help="For bedgraph and depth output, report the depth of this base instead of the total depth (default: total)",
+    )
+    parser.add_argument(
+        "--reference",
+        help="For mpileup output, path to the reference FASTA file (default: reference bases are N)",
+    )
+    parser.add_argument(
+        "--width",
+        type=int,
+        choices=[16, 32, 64],
+        default=64,
+        help="For bedgraph, depth, summary and histogram output, integer width of the counters stored at each position (default: %(default)s)",
+    )
+    parser.add_argument(
+        "--storage",
+        choices=["dense", "sparse", "auto"],
+        default="dense",
+        help="For bedgraph, depth, summary and histogram output, storage of the counters of each reference (default: %(default)s)",
+    )
+    parser.add_argument(
+        "--saturate",
+        action="store_true",
+        default=False,
+        help="For bedgraph, depth, summary and histogram output, stop counters at the maximum value of their width instead of failing (default: %(default)s)",
+    )
+    parser.add_argument(
+        "-o",
+        "--output",
+        help="Path to output file (default: stdout)",
+    )
+    parser.add_argument(
+        "-z",
+        "--bgzip",
+        action="store_true",
+        default=False,
+        help="For tsv output, compress the output with BGZF, so that it can be indexed with tabix (default: %(default)s)",
+    )
 
     args = parser.parse_args()
 
-    columns = [
-        "chrom",
-        "pos",
-        "ins",
-        "cov",
-    ] + [base.lower() for base in api.BASES]
-
-    if args.stats:
-        columns.extend(
-            [f"pc_{base.lower()}" for base in api.BASES]
-            + [
-                "entropy",
-                "secondary_entropy",
-            ]
-        )
+    if args.bgzip and (args.summary or args.histogram or args.format != "tsv"):
+        parser.error("--bgzip is only supported for tsv output")
 
-    writer = csv.writer(sys.stdout, delimiter="\t")
-    writer.writerow(columns)
+    if args.overlaps != "count" and args.format == "mpileup":
+        parser.error("--overlaps is only supported as count for mpileup output")
 
-    data = api.query(
-        bam=args.bam,
-        region=args.region,
-        bai=args.index,
-        mapping_quality=args.mapping_quality,
-        base_quality=args.base_quality,
-    )
-
-    for row in iterate(
-        data, region=args.region, stats=args.stats, decimals=args.decimals
-    ):
-        writer.writerow(row)
+    if args.summary:
+        api.query_summary(
+            bam=args.bam,
+            path=args.output,
+            region=args.region,
+            bai=args.index,
+            mapping_quality=args.mapping_quality,
+            base_quality=args.base_quality,
+            trim_ends=args.trim_ends,
+            read_groups=args.read_groups,
+            tag_filters=args.tag_filters,
+            overlaps=args.overlaps,
+            threads=args.threads,
+            decimals=args.decimals,
+            width=args.width,
+            storage=args.storage,
+            saturate=args.saturate,
+        )
+    elif args.histogram:
+        api.query_histogram(
+            bam=args.bam,
+            path=args.output,
+            region=args.region,
+            bai=args.index,
+            mapping_quality=args.mapping_quality,
+            base_quality=args.base_quality,
+            trim_ends=args.trim_ends,
+            read_groups=args.read_groups,
+            tag_filters=args.tag_filters,
+            overlaps=args.overlaps,
+            threads=args.threads,
+            cap=args.histogram_cap,
+            width=args.width,
+            storage=args.storage,
+            saturate=args.saturate,
+        )
+    elif args.format == "bedgraph":
+        api.query_bedgraph(
+            bam=args.bam,
+            path=args.output,
+            region=args.region,
+            bai=args.index,
+            mapping_quality=args.mapping_quality,
+            base_quality=args.base_quality,
+            trim_ends=args.trim_ends,
+            read_groups=args.read_groups,
+            tag_filters=args.tag_filters,
+            overlaps=args.overlaps,
+            threads=args.threads,
+            base=args.base,
+            width=args.width,
+            storage=args.storage,
+            saturate=args.saturate,
+        )
+    elif args.format == "mpileup":
+        api.query_mpileup(
+            bam=args.bam,
+            path=args.output,
+            region=args.region,
+            bai=args.index,
+            mapping_quality=args.mapping_quality,
+            base_quality=args.base_quality,
+            trim_ends=args.trim_ends,
+            read_groups=args.read_groups,
+            tag_filters=args.tag_filters,
+            threads=args.threads,
+            reference=args.reference,
+        )
+    elif args.format == "depth":
+        api.query_depth(
+            bam=args.bam,
+            path=args.output,
+            region=args.region,
+            bai=args.index,
+            mapping_quality=args.mapping_quality,
+            base_quality=args.base_quality,
+            trim_ends=args.trim_ends,
+            read_groups=args.read_groups,
+            tag_filters=args.tag_filters,
+            overlaps=args.overlaps,
+            threads=args.threads,
+            base=args.base,
+            width=args.width,
+            storage=args.storage,
+            saturate=args.saturate,
+        )
+    else:
+        api.query_tsv(
+            bam=args.bam,
+            path=args.output,
+            region=args.region,
+            bai=args.index,
+            mapping_quality=args.mapping_quality,
+            base_quality=args.base_quality,
+            trim_ends=args.trim_ends,
+            read_groups=args.read_groups,
+            tag_filters=args.tag_filters,
+            overlaps=args.overlaps,
+            threads=args.threads,
+            stats=args.stats,
+            end_distance=args.end_distance,
+            decimals=args.decimals,
+            bgzip=args.bgzip,
+        )
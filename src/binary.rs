@@ -0,0 +1,252 @@
+use noodles::bgzf;
+use std::fs::File;
+use std::io::{Read, Write};
+
+use crate::counts::{CountOptions, CountWidth, Counts};
+use crate::error::MapTideError;
+use crate::options::PileupOptions;
+use crate::qc::ReadStats;
+use crate::{Coordinate, CoordinateMap, InsMaps, Pileup, RefLengths, RefMap};
+
+/// Bytes at the start of every pileup file.
+const MAGIC: [u8; 4] = *b"MTPU";
+
+/// Version of the pileup file format, which is increased whenever the layout changes.
+const VERSION: u32 = 1;
+
+/// Write `x` as a little-endian `u64`.
+fn write_u64<W: Write>(writer: &mut W, x: usize) -> Result<(), MapTideError> {
+    writer.write_all(&(x as u64).to_le_bytes())?;
+    Ok(())
+}
+
+/// Read a little-endian `u64`.
+fn read_u64<R: Read>(reader: &mut R) -> Result<usize, MapTideError> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    usize::try_from(u64::from_le_bytes(buf)).map_err(|_| MapTideError::IntegerOverflow)
+}
+
+/// Write `x` as a little-endian integer of `width`.
+fn write_count<W: Write>(writer: &mut W, x: usize, width: CountWidth) -> Result<(), MapTideError> {
+    match width {
+        CountWidth::U16 => writer.write_all(&(x as u16).to_le_bytes())?,
+        CountWidth::U32 => writer.write_all(&(x as u32).to_le_bytes())?,
+        CountWidth::U64 => writer.write_all(&(x as u64).to_le_bytes())?,
+    }
+    Ok(())
+}
+
+/// Read a little-endian integer of `width`.
+fn read_count<R: Read>(reader: &mut R, width: CountWidth) -> Result<usize, MapTideError> {
+    match width {
+        CountWidth::U16 => {
+            let mut buf = [0; 2];
+            reader.read_exact(&mut buf)?;
+            Ok(usize::from(u16::from_le_bytes(buf)))
+        }
+        CountWidth::U32 => {
+            let mut buf = [0; 4];
+            reader.read_exact(&mut buf)?;
+            Ok(u32::from_le_bytes(buf) as usize)
+        }
+        CountWidth::U64 => read_u64(reader),
+    }
+}
+
+/// Write `s` as its length followed by its UTF-8 bytes.
+fn write_string<W: Write>(writer: &mut W, s: &str) -> Result<(), MapTideError> {
+    write_u64(writer, s.len())?;
+    writer.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+/// Read a string written by `write_string`.
+fn read_string<R: Read>(reader: &mut R) -> Result<String, MapTideError> {
+    let len = read_u64(reader)?;
+    let mut buf = Vec::new();
+    reader.by_ref().take(len as u64).read_to_end(&mut buf)?;
+
+    if buf.len() != len {
+        return Err(MapTideError::InvalidPileupFile);
+    }
+
+    String::from_utf8(buf).map_err(|_| MapTideError::InvalidPileupFile)
+}
+
+/// Read the bytes of `len` rows of counts of `width`.
+///
+/// The bytes are read before any counts are stored, so that a truncated or corrupt file is rejected instead of sizing the counts.
+fn read_rows<R: Read>(
+    reader: &mut R,
+    len: usize,
+    width: CountWidth,
+) -> Result<Vec<u8>, MapTideError> {
+    let n_bytes = len
+        .checked_mul(6 * width.bits() / 8)
+        .ok_or_else(|| MapTideError::InvalidPileupFile)?;
+
+    let mut buf = Vec::new();
+    reader.by_ref().take(n_bytes as u64).read_to_end(&mut buf)?;
+
+    if buf.len() != n_bytes {
+        return Err(MapTideError::InvalidPileupFile);
+    }
+
+    Ok(buf)
+}
+
+/// Write the header, options, and counts of each reference of `pileup`.
+fn write_pileup_data<W: Write>(writer: &mut W, pileup: &Pileup) -> Result<(), MapTideError> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+
+    // Options are stored as JSON, so that new options can be read by later versions
+    write_string(writer, &pileup.options.to_json()?)?;
+    write_string(writer, &serde_json::to_string(&pileup.read_stats)?)?;
+
+    write_u64(writer, pileup.ref_names.len())?;
+
+    for ref_name in pileup.ref_names.iter() {
+        let (ref_arr, offset) = pileup
+            .ref_arrs
+            .get(ref_name)
+            .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+        let ins_map = pileup
+            .ins_maps
+            .get(ref_name)
+            .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+        let width = ref_arr.options().width;
+
+        write_string(writer, ref_name)?;
+        write_u64(writer, pileup.ref_length(ref_name)?)?;
+        write_u64(writer, *offset)?;
+        write_u64(writer, ref_arr.len())?;
+        write_u64(writer, width.bits())?;
+
+        // Counts of every reference position, at the width they were counted with
+        for row in ref_arr.rows() {
+            for count in row.iter() {
+                write_count(writer, *count, width)?;
+            }
+        }
+
+        write_u64(writer, ins_map.len())?;
+
+        for (Coordinate(pos, ins_pos), row) in ins_map.iter() {
+            write_u64(writer, *pos)?;
+            write_u64(writer, *ins_pos)?;
+            for count in row.iter() {
+                write_u64(writer, *count)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a pileup written by `write_pileup_data`.
+fn read_pileup_data<R: Read>(reader: &mut R) -> Result<Pileup, MapTideError> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+
+    if magic != MAGIC {
+        return Err(MapTideError::InvalidPileupFile);
+    }
+
+    let mut version = [0; 4];
+    reader.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+
+    if version != VERSION {
+        return Err(MapTideError::UnsupportedPileupVersion(version));
+    }
+
+    let options = PileupOptions::from_json(&read_string(reader)?)?;
+
+    let read_stats: ReadStats = serde_json::from_str(&read_string(reader)?)?;
+
+    let mut ref_names = Vec::new();
+    let mut ref_arrs = RefMap::new();
+    let mut ins_maps = InsMaps::new();
+    let mut ref_lengths = RefLengths::new();
+
+    for _ in 0..read_u64(reader)? {
+        let ref_name = read_string(reader)?;
+        let ref_length = read_u64(reader)?;
+        let offset = read_u64(reader)?;
+        let len = read_u64(reader)?;
+        let width = CountWidth::from_bits(read_u64(reader)?)?;
+
+        // The counted positions must lie within the reference
+        match offset.checked_add(len) {
+            Some(end) if end <= ref_length => {}
+            _ => return Err(MapTideError::InvalidPileupFile),
+        }
+
+        // Counts are loaded at the width they were written with, and with the storage they were counted with
+        let mut ref_arr = Counts::new(
+            len,
+            CountOptions {
+                width,
+                storage: options.count_options().storage,
+                saturate: options.count_options().saturate,
+            },
+        );
+
+        for (index, mut bytes) in read_rows(reader, len, width)?
+            .chunks_exact(6 * width.bits() / 8)
+            .enumerate()
+        {
+            let mut row = [0; 6];
+            for count in row.iter_mut() {
+                *count = read_count(&mut bytes, width)?;
+            }
+            ref_arr.add_row(index, &row)?;
+        }
+
+        let mut ins_map = CoordinateMap::new();
+
+        for _ in 0..read_u64(reader)? {
+            let pos = read_u64(reader)?;
+            let ins_pos = read_u64(reader)?;
+            let mut row = [0; 6];
+            for count in row.iter_mut() {
+                *count = read_u64(reader)?;
+            }
+            ins_map.insert(Coordinate(pos, ins_pos), row);
+        }
+
+        ref_arrs.insert(ref_name.clone(), (ref_arr, offset));
+        ins_maps.insert(ref_name.clone(), ins_map);
+        ref_lengths.insert(ref_name.clone(), ref_length);
+        ref_names.push(ref_name);
+    }
+
+    Ok(Pileup {
+        ref_names,
+        ref_arrs,
+        ins_maps,
+        ref_lengths,
+        options,
+        read_stats,
+    })
+}
+
+/// Save `pileup` to a BGZF-compressed binary file located at `path`.
+///
+/// The file holds the reference names and lengths, the options the pileup was counted with, the read statistics, the counts of every reference position, and the counts of every insertion.
+pub fn write_pileup(pileup: &Pileup, path: String) -> Result<(), MapTideError> {
+    let mut writer = bgzf::Writer::new(File::create(path)?);
+    write_pileup_data(&mut writer, pileup)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Load a pileup saved with `write_pileup` from the file located at `path`.
+pub fn read_pileup(path: String) -> Result<Pileup, MapTideError> {
+    let mut reader = bgzf::Reader::new(File::open(path)?);
+    read_pileup_data(&mut reader)
+}
@@ -0,0 +1,75 @@
+use arrow::array::{ArrayRef, StringBuilder, UInt64Builder};
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use std::fs::File;
+use std::sync::Arc;
+
+use crate::error::MapTideError;
+use crate::{Coordinate, Pileup, BASES};
+
+/// Schema of the pileup table: `chrom`, `pos`, `ins`, `cov`, followed by a column for each base.
+fn schema() -> Schema {
+    let mut fields = vec![
+        Field::new("chrom", DataType::Utf8, false),
+        Field::new("pos", DataType::UInt64, false),
+        Field::new("ins", DataType::UInt64, false),
+        Field::new("cov", DataType::UInt64, false),
+    ];
+
+    for base in BASES.iter() {
+        fields.push(Field::new(&base.to_lowercase(), DataType::UInt64, false));
+    }
+
+    Schema::new(fields)
+}
+
+/// Build a `RecordBatch` from `pileup`, with a row for each coordinate in sorted order.
+pub fn to_record_batch(pileup: &Pileup) -> Result<RecordBatch, MapTideError> {
+    let mut chroms = StringBuilder::new();
+    let mut positions = UInt64Builder::new();
+    let mut ins_positions = UInt64Builder::new();
+    let mut coverages = UInt64Builder::new();
+    let mut counts: Vec<UInt64Builder> = BASES.iter().map(|_| UInt64Builder::new()).collect();
+
+    for ref_name in pileup.ref_names.iter() {
+        for (Coordinate(pos, ins_pos), row) in pileup.sorted_rows(ref_name)? {
+            chroms.append_value(ref_name);
+            positions.append_value(pos as u64);
+            ins_positions.append_value(ins_pos as u64);
+            coverages.append_value(row.iter().sum::<usize>() as u64);
+
+            for (builder, count) in counts.iter_mut().zip(row.iter()) {
+                builder.append_value(*count as u64);
+            }
+        }
+    }
+
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(chroms.finish()),
+        Arc::new(positions.finish()),
+        Arc::new(ins_positions.finish()),
+        Arc::new(coverages.finish()),
+    ];
+
+    for builder in counts.iter_mut() {
+        columns.push(Arc::new(builder.finish()));
+    }
+
+    Ok(RecordBatch::try_new(Arc::new(schema()), columns)?)
+}
+
+/// Write the counts in `pileup` to a Parquet file located at `parquet_path`.
+pub fn write_parquet(pileup: &Pileup, parquet_path: String) -> Result<(), MapTideError> {
+    let batch = to_record_batch(pileup)?;
+
+    // Create the output file
+    let file = File::create(parquet_path)?;
+
+    // Write the batch as a single row group
+    let mut writer = ArrowWriter::try_new(file, batch.schema(), None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+
+    Ok(())
+}
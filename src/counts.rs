@@ -0,0 +1,381 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::error::MapTideError;
+
+/// Positions with counts in a sparse store are moved to a dense store once more than one in `AUTO_DENSE_RATIO` positions have counts.
+const AUTO_DENSE_RATIO: usize = 4;
+
+/// Integer width of the counters stored at each position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "usize", into = "usize")]
+pub enum CountWidth {
+    U16,
+    U32,
+    U64,
+}
+
+impl CountWidth {
+    /// Return the width with the given number of `bits`, which is one of 16, 32 or 64.
+    pub fn from_bits(bits: usize) -> Result<Self, MapTideError> {
+        match bits {
+            16 => Ok(CountWidth::U16),
+            32 => Ok(CountWidth::U32),
+            64 => Ok(CountWidth::U64),
+            _ => Err(MapTideError::InvalidCountWidth),
+        }
+    }
+
+    /// Return the number of bits of the width.
+    pub fn bits(&self) -> usize {
+        match self {
+            CountWidth::U16 => 16,
+            CountWidth::U32 => 32,
+            CountWidth::U64 => 64,
+        }
+    }
+}
+
+impl TryFrom<usize> for CountWidth {
+    type Error = MapTideError;
+
+    fn try_from(bits: usize) -> Result<Self, Self::Error> {
+        Self::from_bits(bits)
+    }
+}
+
+impl From<CountWidth> for usize {
+    fn from(width: CountWidth) -> Self {
+        width.bits()
+    }
+}
+
+/// How the counters of each reference are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    /// A counter for every position of the reference.
+    Dense,
+
+    /// Counters only for positions that have been counted.
+    Sparse,
+
+    /// Sparse counters, which become dense once enough of the reference has been counted.
+    Auto,
+}
+
+impl Storage {
+    /// Return the storage named `name`, which is one of `dense`, `sparse` or `auto`.
+    pub fn from_name(name: &str) -> Result<Self, MapTideError> {
+        match name.to_ascii_lowercase().as_str() {
+            "dense" => Ok(Storage::Dense),
+            "sparse" => Ok(Storage::Sparse),
+            "auto" => Ok(Storage::Auto),
+            _ => Err(MapTideError::InvalidStorage),
+        }
+    }
+
+    /// Return the name of the storage.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Storage::Dense => "dense",
+            Storage::Sparse => "sparse",
+            Storage::Auto => "auto",
+        }
+    }
+}
+
+/// Options for storing the counts of each reference.
+///
+/// If `saturate` is `true`, counters stop at the maximum value of their width. Otherwise, exceeding it is a `MapTideError::IntegerOverflow`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CountOptions {
+    pub width: CountWidth,
+    pub storage: Storage,
+    pub saturate: bool,
+}
+
+impl CountOptions {
+    /// Create options from a width in `bits`, the `storage` name, and whether counters `saturate`.
+    pub fn new(bits: usize, storage: &str, saturate: bool) -> Result<Self, MapTideError> {
+        Ok(Self {
+            width: CountWidth::from_bits(bits)?,
+            storage: Storage::from_name(storage)?,
+            saturate,
+        })
+    }
+}
+
+impl Default for CountOptions {
+    fn default() -> Self {
+        Self {
+            width: CountWidth::U64,
+            storage: Storage::Dense,
+            saturate: false,
+        }
+    }
+}
+
+/// Storage that bases can be counted into, indexed from the first stored position.
+pub trait Counter {
+    /// Add one to the count of the base at `base_index` for the position at `index`.
+    fn increment(&mut self, index: usize, base_index: usize) -> Result<(), MapTideError>;
+}
+
+impl Counter for Vec<[usize; 6]> {
+    fn increment(&mut self, index: usize, base_index: usize) -> Result<(), MapTideError> {
+        self[index][base_index] += 1;
+        Ok(())
+    }
+}
+
+/// An unsigned integer that a count can be stored in.
+trait Value: Copy + Default {
+    const MAX: usize;
+
+    fn to_usize(self) -> usize;
+
+    /// Convert `x`, which must be no greater than `Self::MAX`.
+    fn from_usize(x: usize) -> Self;
+}
+
+macro_rules! impl_value {
+    ($t:ty) => {
+        impl Value for $t {
+            const MAX: usize = <$t>::MAX as usize;
+
+            fn to_usize(self) -> usize {
+                self as usize
+            }
+
+            fn from_usize(x: usize) -> Self {
+                x as $t
+            }
+        }
+    };
+}
+
+impl_value!(u16);
+impl_value!(u32);
+impl_value!(usize);
+
+/// Add `n` to `value`, saturating at `T::MAX` if `saturate` is `true`.
+fn add_value<T: Value>(value: &mut T, n: usize, saturate: bool) -> Result<(), MapTideError> {
+    match value.to_usize().checked_add(n) {
+        Some(total) if total <= T::MAX => {
+            *value = T::from_usize(total);
+            Ok(())
+        }
+        _ if saturate => {
+            *value = T::from_usize(T::MAX);
+            Ok(())
+        }
+        _ => Err(MapTideError::IntegerOverflow),
+    }
+}
+
+fn to_row<T: Value>(row: &[T; 6]) -> [usize; 6] {
+    row.map(|x| x.to_usize())
+}
+
+/// Counters of a single width, stored either densely or sparsely.
+#[derive(Clone)]
+enum Store<T> {
+    Dense(Vec<[T; 6]>),
+    Sparse(BTreeMap<usize, [T; 6]>),
+}
+
+impl<T: Value> Store<T> {
+    fn new(len: usize, storage: Storage) -> Self {
+        match storage {
+            Storage::Dense => Store::Dense(vec![[T::default(); 6]; len]),
+            Storage::Sparse | Storage::Auto => Store::Sparse(BTreeMap::new()),
+        }
+    }
+
+    /// Add `n` to the count of the base at `base_index` for the position at `index`.
+    fn add(
+        &mut self,
+        index: usize,
+        base_index: usize,
+        n: usize,
+        saturate: bool,
+    ) -> Result<(), MapTideError> {
+        let row = match self {
+            Store::Dense(arr) => arr
+                .get_mut(index)
+                .ok_or_else(|| MapTideError::IndexNotFound)?,
+            Store::Sparse(map) => map.entry(index).or_insert_with(|| [T::default(); 6]),
+        };
+        add_value(&mut row[base_index], n, saturate)
+    }
+
+    /// Return the counts of the position at `index`, if it is within the store.
+    fn get(&self, index: usize) -> Option<[usize; 6]> {
+        match self {
+            Store::Dense(arr) => arr.get(index).map(to_row),
+            Store::Sparse(map) => Some(map.get(&index).map_or([0; 6], to_row)),
+        }
+    }
+
+    /// Number of positions that have been counted, if the store is sparse.
+    fn sparse_len(&self) -> Option<usize> {
+        match self {
+            Store::Dense(_) => None,
+            Store::Sparse(map) => Some(map.len()),
+        }
+    }
+
+    /// Move the counters into a dense store of `len` positions.
+    fn densify(&mut self, len: usize) {
+        if let Store::Sparse(map) = self {
+            let mut arr = vec![[T::default(); 6]; len];
+            for (index, row) in map.iter() {
+                arr[*index] = *row;
+            }
+            *self = Store::Dense(arr);
+        }
+    }
+
+    /// Return an iterator over the counts of the first `len` positions.
+    fn rows(&self, len: usize) -> Box<dyn Iterator<Item = [usize; 6]> + '_> {
+        match self {
+            Store::Dense(arr) => Box::new(arr.iter().map(to_row)),
+            Store::Sparse(map) => {
+                let mut entries = map.iter().peekable();
+                Box::new((0..len).map(move |i| match entries.peek() {
+                    Some(&(&index, row)) if index == i => {
+                        let row = to_row(row);
+                        entries.next();
+                        row
+                    }
+                    _ => [0; 6],
+                }))
+            }
+        }
+    }
+
+    /// Return an iterator over the positions that may have counts, and their counts.
+    fn entries(&self) -> Box<dyn Iterator<Item = (usize, [usize; 6])> + '_> {
+        match self {
+            Store::Dense(arr) => Box::new(arr.iter().map(to_row).enumerate()),
+            Store::Sparse(map) => Box::new(map.iter().map(|(index, row)| (*index, to_row(row)))),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Data {
+    U16(Store<u16>),
+    U32(Store<u32>),
+    U64(Store<usize>),
+}
+
+/// Evaluate `$call` with `$store` bound to the store of `$data`, whatever its width.
+macro_rules! dispatch {
+    ($data:expr, $store:ident => $call:expr) => {
+        match $data {
+            Data::U16($store) => $call,
+            Data::U32($store) => $call,
+            Data::U64($store) => $call,
+        }
+    };
+}
+
+/// Base counts over a run of consecutive reference positions.
+#[derive(Clone)]
+pub struct Counts {
+    len: usize,
+    options: CountOptions,
+    data: Data,
+}
+
+impl Counts {
+    /// Create zeroed counts over `len` positions, stored according to `options`.
+    pub fn new(len: usize, options: CountOptions) -> Self {
+        let data = match options.width {
+            CountWidth::U16 => Data::U16(Store::new(len, options.storage)),
+            CountWidth::U32 => Data::U32(Store::new(len, options.storage)),
+            CountWidth::U64 => Data::U64(Store::new(len, options.storage)),
+        };
+
+        Self { len, options, data }
+    }
+
+    /// Number of positions covered by the counts.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Options that the counts are stored with.
+    pub fn options(&self) -> CountOptions {
+        self.options
+    }
+
+    /// Check whether the counts cover no positions.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the counts of the position at `index`.
+    pub fn get(&self, index: usize) -> Result<[usize; 6], MapTideError> {
+        if index >= self.len {
+            return Err(MapTideError::IndexNotFound);
+        }
+
+        dispatch!(&self.data, store => store.get(index)).ok_or_else(|| MapTideError::IndexNotFound)
+    }
+
+    /// Add `n` to the count of the base at `base_index` for the position at `index`.
+    pub fn add(&mut self, index: usize, base_index: usize, n: usize) -> Result<(), MapTideError> {
+        if index >= self.len {
+            return Err(MapTideError::IndexNotFound);
+        }
+
+        let saturate = self.options.saturate;
+        dispatch!(&mut self.data, store => store.add(index, base_index, n, saturate))?;
+
+        // Switch to dense counters once they would use less memory
+        if self.options.storage == Storage::Auto {
+            let len = self.len;
+            dispatch!(&mut self.data, store => {
+                if store.sparse_len().map_or(false, |x| x * AUTO_DENSE_RATIO > len) {
+                    store.densify(len);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Add each count of `row` to the position at `index`.
+    pub fn add_row(&mut self, index: usize, row: &[usize; 6]) -> Result<(), MapTideError> {
+        for (base_index, n) in row.iter().enumerate() {
+            if *n > 0 {
+                self.add(index, base_index, *n)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add every count of `other` to the positions starting at `start`.
+    pub fn add_counts(&mut self, start: usize, other: &Counts) -> Result<(), MapTideError> {
+        for (index, row) in dispatch!(&other.data, store => store.entries()) {
+            self.add_row(start + index, &row)?;
+        }
+        Ok(())
+    }
+
+    /// Return an iterator over the counts of every position, in order.
+    pub fn rows(&self) -> Box<dyn Iterator<Item = [usize; 6]> + '_> {
+        let len = self.len;
+        dispatch!(&self.data, store => store.rows(len))
+    }
+}
+
+impl Counter for Counts {
+    fn increment(&mut self, index: usize, base_index: usize) -> Result<(), MapTideError> {
+        self.add(index, base_index, 1)
+    }
+}
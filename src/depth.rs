@@ -0,0 +1,112 @@
+use std::io::{BufWriter, Write};
+
+use crate::error::MapTideError;
+use crate::{get_writer, Pileup};
+
+/// Return the depth of `row`, which is either the count of the base at `base_index`, or the total of all counts.
+pub(crate) fn depth(row: &[usize; 6], base_index: Option<usize>) -> usize {
+    match base_index {
+        Some(i) => row[i],
+        None => row.iter().sum(),
+    }
+}
+
+/// Write the depth at each reference position in `pileup` as bedGraph intervals.
+///
+/// Consecutive positions with the same depth are merged into a single interval.
+fn write_intervals<W: Write>(
+    writer: &mut W,
+    pileup: &Pileup,
+    base_index: Option<usize>,
+) -> Result<(), MapTideError> {
+    for ref_name in pileup.ref_names.iter() {
+        let (ref_arr, offset) = pileup
+            .ref_arrs
+            .get(ref_name)
+            .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+        // Start of the current interval (0-based) and its depth
+        let mut current: Option<(usize, usize)> = None;
+
+        for (i, row) in ref_arr.rows().enumerate() {
+            let row_depth = depth(&row, base_index);
+
+            match current {
+                Some((_, d)) if d == row_depth => {}
+                Some((start, d)) => {
+                    writeln!(writer, "{}\t{}\t{}\t{}", ref_name, start, i + offset, d)?;
+                    current = Some((i + offset, row_depth));
+                }
+                None => current = Some((i + offset, row_depth)),
+            }
+        }
+
+        // Write the final interval
+        if let Some((start, d)) = current {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}",
+                ref_name,
+                start,
+                ref_arr.len() + offset,
+                d
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the depth at each reference position in `pileup` as `samtools depth` lines.
+fn write_positions<W: Write>(
+    writer: &mut W,
+    pileup: &Pileup,
+    base_index: Option<usize>,
+) -> Result<(), MapTideError> {
+    for ref_name in pileup.ref_names.iter() {
+        let (ref_arr, offset) = pileup
+            .ref_arrs
+            .get(ref_name)
+            .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+        for (i, row) in ref_arr.rows().enumerate() {
+            writeln!(
+                writer,
+                "{}\t{}\t{}",
+                ref_name,
+                i + offset + 1,
+                depth(&row, base_index)
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write a bedGraph track of the depth in `pileup` to `output_path`, or to stdout if `output_path` is `None`.
+///
+/// If `base_index` is `Some`, the depth is the count of that base (or of deletions). Otherwise, it is the total count.
+pub fn write_bedgraph(
+    pileup: &Pileup,
+    output_path: Option<String>,
+    base_index: Option<usize>,
+) -> Result<(), MapTideError> {
+    let mut writer = BufWriter::new(get_writer(output_path)?);
+    write_intervals(&mut writer, pileup, base_index)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write the depth in `pileup` in the format of `samtools depth -a` to `output_path`, or to stdout if `output_path` is `None`.
+///
+/// If `base_index` is `Some`, the depth is the count of that base (or of deletions). Otherwise, it is the total count.
+pub fn write_depth(
+    pileup: &Pileup,
+    output_path: Option<String>,
+    base_index: Option<usize>,
+) -> Result<(), MapTideError> {
+    let mut writer = BufWriter::new(get_writer(output_path)?);
+    write_positions(&mut writer, pileup, base_index)?;
+    writer.flush()?;
+    Ok(())
+}
@@ -1,4 +1,6 @@
+use arrow::error::ArrowError;
 use noodles::core::region;
+use parquet::errors::ParquetError;
 use std::error::Error;
 use std::fmt::{self, Display};
 use std::io;
@@ -8,14 +10,29 @@ pub enum MapTideError {
     KeyNotFound,
     IndexNotFound,
     InvalidBase,
+    InvalidCountWidth,
+    InvalidStorage,
+    InvalidOption(String),
+    InvalidTagFilter(String),
     IntegerOverflow,
     AlignmentStartNotFound,
     AlignmentEndNotFound,
     MappingQualityNotFound,
     QualityScoreNotFound,
     ReferenceSequenceIDNotFound,
+    UnsortedRecords,
+    InvalidHeader,
+    InvalidPileupFile,
+    UnsupportedPileupVersion(u32),
+    ReferenceMismatch(String),
+    NoPileups,
     IOError(io::Error),
     ParseError(region::ParseError),
+    ArrowError(ArrowError),
+    ParquetError(ParquetError),
+    JsonError(serde_json::Error),
+    TomlDeError(toml::de::Error),
+    TomlSerError(toml::ser::Error),
 }
 
 impl From<io::Error> for MapTideError {
@@ -30,20 +47,71 @@ impl From<region::ParseError> for MapTideError {
     }
 }
 
+impl From<ArrowError> for MapTideError {
+    fn from(e: ArrowError) -> Self {
+        MapTideError::ArrowError(e)
+    }
+}
+
+impl From<ParquetError> for MapTideError {
+    fn from(e: ParquetError) -> Self {
+        MapTideError::ParquetError(e)
+    }
+}
+
+impl From<serde_json::Error> for MapTideError {
+    fn from(e: serde_json::Error) -> Self {
+        MapTideError::JsonError(e)
+    }
+}
+
+impl From<toml::de::Error> for MapTideError {
+    fn from(e: toml::de::Error) -> Self {
+        MapTideError::TomlDeError(e)
+    }
+}
+
+impl From<toml::ser::Error> for MapTideError {
+    fn from(e: toml::ser::Error) -> Self {
+        MapTideError::TomlSerError(e)
+    }
+}
+
 impl Display for MapTideError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             MapTideError::KeyNotFound => f.write_str("KeyNotFound"),
             MapTideError::IndexNotFound => f.write_str("IndexNotFound"),
             MapTideError::InvalidBase => f.write_str("InvalidBase"),
+            MapTideError::InvalidCountWidth => f.write_str("InvalidCountWidth"),
+            MapTideError::InvalidStorage => f.write_str("InvalidStorage"),
+            MapTideError::InvalidOption(ref name) => write!(f, "InvalidOption({})", name),
+            MapTideError::InvalidTagFilter(ref expression) => {
+                write!(f, "InvalidTagFilter({})", expression)
+            }
             MapTideError::IntegerOverflow => f.write_str("IntegerOverlow"),
             MapTideError::AlignmentStartNotFound => f.write_str("AlignmentStartNotFound"),
             MapTideError::AlignmentEndNotFound => f.write_str("AlignmentEndNotFound"),
             MapTideError::MappingQualityNotFound => f.write_str("MappingQualityNotFound"),
             MapTideError::QualityScoreNotFound => f.write_str("QualityScoreNotFound"),
             MapTideError::ReferenceSequenceIDNotFound => f.write_str("ReferenceSequenceIDNotFound"),
+            MapTideError::UnsortedRecords => f.write_str("UnsortedRecords"),
+            MapTideError::InvalidHeader => f.write_str("InvalidHeader"),
+            MapTideError::InvalidPileupFile => f.write_str("InvalidPileupFile"),
+            MapTideError::UnsupportedPileupVersion(version) => {
+                write!(f, "UnsupportedPileupVersion({})", version)
+            }
+            MapTideError::ReferenceMismatch(ref name) => {
+                write!(f, "ReferenceMismatch({})", name)
+            }
+            MapTideError::NoPileups => f.write_str("NoPileups"),
             MapTideError::IOError(ref _e) => f.write_str("IOError"),
             MapTideError::ParseError(ref _e) => f.write_str("ParseError"),
+            MapTideError::ArrowError(ref _e) => f.write_str("ArrowError"),
+            MapTideError::ParquetError(ref _e) => f.write_str("ParquetError"),
+            MapTideError::JsonError(ref _e) => f.write_str("JsonError"),
+            MapTideError::TomlDeError(ref _e) => f.write_str("TomlDeError"),
+            MapTideError::TomlSerError(ref _e) => f.write_str("TomlSerError"),
         }
     }
 }
@@ -53,6 +121,11 @@ impl Error for MapTideError {
         match *self {
             MapTideError::IOError(ref e) => Some(e),
             MapTideError::ParseError(ref e) => Some(e),
+            MapTideError::ArrowError(ref e) => Some(e),
+            MapTideError::ParquetError(ref e) => Some(e),
+            MapTideError::JsonError(ref e) => Some(e),
+            MapTideError::TomlDeError(ref e) => Some(e),
+            MapTideError::TomlSerError(ref e) => Some(e),
             _ => None,
         }
     }
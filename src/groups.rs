@@ -0,0 +1,243 @@
+use noodles::bam::bai;
+use noodles::core::Region;
+use noodles::sam::alignment::Record;
+use noodles::sam::record::data::field::Tag;
+use noodles::sam::Header;
+use serde::{Deserialize, Serialize};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+use crate::error::MapTideError;
+use crate::options::{FilterReason, PileupOptions};
+use crate::overlap::{MateOverlaps, OverlapMask};
+use crate::qc::ReadStats;
+use crate::{
+    count_record, get_reader_and_header, init_coordinates, init_maps, read_records, within_region,
+    Pileup,
+};
+
+/// Name of the group of records without a read group, or whose read group has no sample.
+pub const NO_GROUP: &str = "*";
+
+/// How records are split into separate pileups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupBy {
+    /// A pileup for each read group, from the `RG` tag of each record.
+    ReadGroup,
+
+    /// A pileup for each sample, from the `SM` field of the read group of each record in the header.
+    Sample,
+}
+
+impl GroupBy {
+    /// Return the grouping named `name`, which is one of `read_group` or `sample`.
+    pub fn from_name(name: &str) -> Result<Self, MapTideError> {
+        match name.to_ascii_lowercase().as_str() {
+            "read_group" => Ok(GroupBy::ReadGroup),
+            "sample" => Ok(GroupBy::Sample),
+            _ => Err(MapTideError::InvalidOption("group_by".to_string())),
+        }
+    }
+
+    /// Return the name of the grouping.
+    pub fn name(&self) -> &'static str {
+        match self {
+            GroupBy::ReadGroup => "read_group",
+            GroupBy::Sample => "sample",
+        }
+    }
+}
+
+/// Return the read group of `record`, from its `RG` tag.
+pub fn read_group(record: &Record) -> Option<&str> {
+    record
+        .data()
+        .get(Tag::ReadGroup)
+        .and_then(|field| field.value().as_str())
+}
+
+/// Return an empty pileup over the references in `header`, or over `region` if it is `Some`.
+fn empty_pileup(
+    header: &Header,
+    region: Option<&Region>,
+    options: &PileupOptions,
+) -> Result<Pileup, MapTideError> {
+    let (mut ref_arrs, mut ins_maps, mut ref_lengths) = init_maps();
+
+    for (ref_name, ref_seq) in header.reference_sequences().iter() {
+        ref_lengths.insert(ref_name.to_owned(), ref_seq.length().get());
+    }
+
+    init_coordinates(
+        &mut ref_arrs,
+        &mut ins_maps,
+        &ref_lengths,
+        region,
+        options.count_options(),
+    )?;
+
+    let ref_names = match region {
+        Some(reg) => vec![reg.name().to_owned()],
+        None => header.reference_sequences().keys().cloned().collect(),
+    };
+
+    Ok(Pileup {
+        ref_names,
+        ref_arrs,
+        ins_maps,
+        ref_lengths,
+        options: options.clone(),
+        read_stats: ReadStats::default(),
+    })
+}
+
+/// Count the bases of the BAM file located at `bam_path` into a separate pileup for each group of records, in a single scan.
+///
+/// If `region` is `Some`, only the bases within it are counted, and the index located at `bai_path` is used to fetch the records intersecting it if provided.
+///
+/// Every read group in the header that passes the read group filter of `options` has a pileup, even if it has no records. Records without a read group, or whose read group has no sample when grouping by sample, are counted in the group `NO_GROUP`.
+pub fn pileup_groups(
+    bam_path: String,
+    bai_path: Option<String>,
+    region: Option<&Region>,
+    group_by: GroupBy,
+    options: &PileupOptions,
+) -> Result<BTreeMap<String, Pileup>, MapTideError> {
+    let base_quality = options.base_quality();
+    let trim_ends = options.trim_ends();
+    let threads = options.threads();
+
+    let (mut reader, header) = get_reader_and_header(bam_path, threads)?;
+    let ref_seqs = header.reference_sequences();
+
+    // Group of each read group in the header
+    let groups: HashMap<String, String> = header
+        .read_groups()
+        .iter()
+        .filter_map(|(id, read_group)| {
+            let group = match group_by {
+                GroupBy::ReadGroup => Some(id.to_owned()),
+                GroupBy::Sample => read_group.sample().map(|x| x.to_owned()),
+            };
+            group.map(|x| (id.to_owned(), x))
+        })
+        .collect();
+
+    let group_of = |record: &Record| -> String {
+        read_group(record)
+            .and_then(|id| match group_by {
+                GroupBy::ReadGroup => Some(id.to_owned()),
+                GroupBy::Sample => groups.get(id).cloned(),
+            })
+            .unwrap_or_else(|| NO_GROUP.to_string())
+    };
+
+    let mut pileups = BTreeMap::new();
+
+    for (id, group) in groups.iter() {
+        if options.read_groups().is_empty() || options.read_groups().contains(id) {
+            pileups.insert(group.clone(), empty_pileup(&header, region, options)?);
+        }
+    }
+
+    let index;
+    let records: Box<dyn Iterator<Item = io::Result<Record>> + '_> = match (region, bai_path) {
+        (Some(reg), Some(b_path)) => {
+            index = bai::read(b_path)?;
+            Box::new(reader.query(ref_seqs, &index, reg)?)
+        }
+        _ => read_records(reader, threads),
+    };
+
+    let mut overlaps = MateOverlaps::new(options.overlaps(), base_quality, trim_ends);
+
+    let count = |pileups: &mut BTreeMap<String, Pileup>,
+                 record: Record,
+                 mask: OverlapMask|
+     -> Result<(), MapTideError> {
+        let ref_name = ref_seqs
+            .get_index(
+                record
+                    .reference_sequence_id()
+                    .ok_or_else(|| MapTideError::ReferenceSequenceIDNotFound)?,
+            )
+            .ok_or_else(|| MapTideError::IndexNotFound)?
+            .0;
+
+        let pileup = pileups
+            .get_mut(&group_of(&record))
+            .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+        let ref_length = pileup.ref_length(ref_name)?;
+        let (region_start, region_end) = match region {
+            Some(reg) => (
+                reg.interval().start().map_or(1, |x| x.get()),
+                reg.interval().end().map_or(ref_length, |x| x.get()),
+            ),
+            None => (1, ref_length),
+        };
+
+        let (ref_arr, offset) = pileup
+            .ref_arrs
+            .get_mut(ref_name)
+            .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+        let ins_map = pileup
+            .ins_maps
+            .get_mut(ref_name)
+            .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+        count_record(
+            ref_arr,
+            *offset,
+            ins_map,
+            &record,
+            &mask,
+            base_quality,
+            trim_ends,
+            region_start,
+            region_end,
+        )
+    };
+
+    for result in records {
+        let record = result?;
+
+        // Records outside the region are skipped before filtering, as they may lack fields that the filters need
+        if let Some(reg) = region {
+            if !within_region(&record, ref_seqs, reg)? {
+                continue;
+            }
+        }
+
+        let reason = options.filter_reason(&record)?;
+
+        // Records from excluded read groups do not belong to any pileup
+        if reason == Some(FilterReason::ReadGroup) {
+            continue;
+        }
+
+        let pileup = match pileups.entry(group_of(&record)) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(empty_pileup(&header, region, options)?),
+        };
+        pileup.read_stats.add_record(&record, reason);
+
+        if reason.is_some() {
+            continue;
+        }
+
+        for (record, mask) in overlaps.push(record)? {
+            count(&mut pileups, record, mask)?;
+        }
+    }
+
+    // Count the first mates whose mate was never reached
+    for (record, mask) in overlaps.finish() {
+        count(&mut pileups, record, mask)?;
+    }
+
+    Ok(pileups)
+}
@@ -1,69 +1,527 @@
+//! Base frequencies at each position of a BAM file.
+//!
+//! A pileup is counted with [`pileup_all`] or [`pileup_query`], which return a [`Pileup`] of the counts at each reference position and insertion. Records can also be counted one at a time with [`count_record`].
+//!
+//! Python bindings are built with the `python` feature.
+
 use noodles::bam::bai;
-use noodles::core::region::{Interval, ParseError};
+use noodles::core::region::Interval;
 use noodles::core::{Position, Region};
 use noodles::sam::alignment::Record;
+use noodles::sam::header::ReferenceSequences;
 use noodles::sam::record::cigar::op::Kind;
 use noodles::sam::record::sequence::{Base, Sequence};
 use noodles::sam::record::{Flags, QualityScores};
-use pyo3::exceptions::{PyException, PyIOError, PyIndexError, PyKeyError, PyOverflowError};
-use pyo3::prelude::*;
-use std::collections::HashMap;
+use noodles::sam::Header;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
+use std::io::{self, Write};
+use std::iter;
+use std::num::NonZeroUsize;
+use std::ops::Bound;
+use std::sync::mpsc;
+use std::thread;
+
+pub mod binary;
+pub mod columnar;
+pub mod counts;
+pub mod depth;
+pub mod error;
+pub mod groups;
+pub mod mpileup;
+pub mod options;
+pub mod overlap;
+mod parallel;
+#[cfg(feature = "python")]
+mod python;
+pub mod qc;
+pub mod quality;
+pub mod stream;
+pub mod summary;
+pub mod tags;
+pub mod tsv;
+pub use counts::{CountOptions, CountWidth, Counter, Counts, Storage};
+pub use error::MapTideError;
+pub use groups::{pileup_groups, GroupBy};
+pub use options::PileupOptions;
+pub use overlap::{MateOverlaps, OverlapMask, OverlapMode, Resolution};
+pub use qc::ReadStats;
+pub use stream::PileupStream;
+pub use tags::TagFilter;
+
+/// Names of the counted bases, in the order they are stored in each row.
+pub const BASES: [&str; 6] = ["A", "C", "G", "T", "DS", "N"];
+
+/// Index of the deletion count within each row.
+pub const DELETION_INDEX: usize = 4;
+
+/// Index of the N count within each row.
+pub const N_INDEX: usize = 5;
+
+/// A reference position, and the position within an insertion after it (or zero for the reference base).
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct Coordinate(pub usize, pub usize);
 
-mod error;
-use error::MapTideError;
+/// Counts of inserted bases, sorted by coordinate.
+pub type CoordinateMap = BTreeMap<Coordinate, [usize; 6]>;
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
-struct Coordinate(usize, usize);
+/// Counts over consecutive reference positions.
+pub type RefArr = Vec<[usize; 6]>;
 
-impl IntoPy<PyObject> for Coordinate {
-    fn into_py(self, py: Python<'_>) -> PyObject {
-        (self.0, self.1).into_py(py)
-    }
+type RefMap = HashMap<String, (Counts, usize)>;
+
+type InsMaps = HashMap<String, CoordinateMap>;
+
+type RefLengths = HashMap<String, usize>;
+
+type BamReader = noodles::bam::Reader<noodles::bgzf::Reader<File>>;
+
+/// Number of records decoded at a time when decoding on a separate thread.
+const BATCH_SIZE: usize = 1024;
+
+/// Counts obtained from a pileup, prior to conversion into an output format.
+#[derive(Clone)]
+pub struct Pileup {
+    // Names of the counted references, in the order they appear in the BAM header
+    ref_names: Vec<String>,
+    ref_arrs: RefMap,
+    ins_maps: InsMaps,
+    // Lengths of the references in the BAM header
+    ref_lengths: RefLengths,
+    // Options that the pileup was counted with
+    options: PileupOptions,
+    // Statistics of the records visited while counting
+    read_stats: ReadStats,
 }
 
-impl From<MapTideError> for PyErr {
-    fn from(e: MapTideError) -> Self {
-        match e {
-            MapTideError::KeyNotFound => PyKeyError::new_err(e.to_string()),
-            MapTideError::IndexNotFound => PyIndexError::new_err(e.to_string()),
-            MapTideError::IntegerOverflow => PyOverflowError::new_err(e.to_string()),
-            MapTideError::IOError(e) => PyIOError::new_err(e.to_string()),
-            _ => PyException::new_err(e.to_string()),
+impl Pileup {
+    /// Names of the counted references, in the order they appear in the BAM header.
+    pub fn ref_names(&self) -> &[String] {
+        &self.ref_names
+    }
+
+    /// Length of `ref_name` in the BAM header.
+    pub fn ref_length(&self, ref_name: &str) -> Result<usize, MapTideError> {
+        self.ref_lengths
+            .get(ref_name)
+            .copied()
+            .ok_or_else(|| MapTideError::KeyNotFound)
+    }
+
+    /// Options that the pileup was counted with.
+    pub fn options(&self) -> &PileupOptions {
+        &self.options
+    }
+
+    /// Statistics of the records visited while counting the pileup.
+    pub fn read_stats(&self) -> &ReadStats {
+        &self.read_stats
+    }
+
+    /// Return an iterator over the coordinates and counts of `ref_name`, sorted by coordinate.
+    ///
+    /// Reference positions and insertions are both stored in order, so they are merged without sorting.
+    pub fn sorted_rows(
+        &self,
+        ref_name: &str,
+    ) -> Result<impl Iterator<Item = (Coordinate, [usize; 6])> + '_, MapTideError> {
+        let (ref_arr, offset) = self
+            .ref_arrs
+            .get(ref_name)
+            .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+        let ins_map = self
+            .ins_maps
+            .get(ref_name)
+            .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+        let mut ref_rows = ref_arr
+            .rows()
+            .enumerate()
+            .map(move |(i, row)| (Coordinate(i + offset + 1, 0), row))
+            .peekable();
+
+        let mut ins_rows = ins_map.iter().map(|(coord, row)| (*coord, *row)).peekable();
+
+        Ok(iter::from_fn(move || {
+            match (ref_rows.peek(), ins_rows.peek()) {
+                (Some((ref_coord, _)), Some((ins_coord, _))) if ins_coord < ref_coord => {
+                    ins_rows.next()
+                }
+                (Some(_), _) => ref_rows.next(),
+                (None, _) => ins_rows.next(),
+            }
+        }))
+    }
+
+    /// Return the first coordinate and counts of `ref_name` after `coord`, or from the start of the reference if `coord` is `None`.
+    ///
+    /// Rows are visited in the same order as `sorted_rows`, without holding a borrow of the pileup between them.
+    pub fn next_row(
+        &self,
+        ref_name: &str,
+        coord: Option<Coordinate>,
+    ) -> Result<Option<(Coordinate, [usize; 6])>, MapTideError> {
+        let (ref_arr, offset) = self
+            .ref_arrs
+            .get(ref_name)
+            .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+        let ins_map = self
+            .ins_maps
+            .get(ref_name)
+            .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+        // The next reference position follows the position of `coord`, including its insertions
+        let pos = coord.map_or(offset + 1, |x| (x.0 + 1).max(offset + 1));
+        let ref_row = if pos <= offset + ref_arr.len() {
+            Some((Coordinate(pos, 0), ref_arr.get(pos - offset - 1)?))
+        } else {
+            None
+        };
+
+        let ins_row = match coord {
+            Some(c) => ins_map.range((Bound::Excluded(c), Bound::Unbounded)).next(),
+            None => ins_map.iter().next(),
         }
+        .map(|(c, row)| (*c, *row));
+
+        Ok(match (ref_row, ins_row) {
+            (Some((ref_coord, _)), Some((ins_coord, row))) if ins_coord < ref_coord => {
+                Some((ins_coord, row))
+            }
+            (Some(ref_row), _) => Some(ref_row),
+            (None, ins_row) => ins_row,
+        })
     }
-}
 
-type CoordinateMap = HashMap<Coordinate, [usize; 6]>;
+    /// Return the first and last reference positions of `ref_name` that were counted.
+    pub fn bounds(&self, ref_name: &str) -> Result<(usize, usize), MapTideError> {
+        let (ref_arr, offset) = self
+            .ref_arrs
+            .get(ref_name)
+            .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+        Ok((offset + 1, offset + ref_arr.len()))
+    }
+
+    /// Return the counts at `coord` of `ref_name`.
+    ///
+    /// Insertion coordinates that were never observed have zero counts.
+    pub fn frequencies(
+        &self,
+        ref_name: &str,
+        coord: Coordinate,
+    ) -> Result<[usize; 6], MapTideError> {
+        let (ref_arr, offset) = self
+            .ref_arrs
+            .get(ref_name)
+            .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+        if coord.0 <= *offset {
+            return Err(MapTideError::IndexNotFound);
+        }
 
-type RefArr = Vec<[usize; 6]>;
+        if coord.1 == 0 {
+            ref_arr.get(coord.0 - offset - 1)
+        } else if coord.0 > offset + ref_arr.len() {
+            Err(MapTideError::IndexNotFound)
+        } else {
+            let ins_map = self
+                .ins_maps
+                .get(ref_name)
+                .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+            Ok(ins_map.get(&coord).copied().unwrap_or([0; 6]))
+        }
+    }
 
-type RefMap = HashMap<String, (RefArr, usize)>;
+    /// Return the total count at `coord` of `ref_name`.
+    pub fn depth(&self, ref_name: &str, coord: Coordinate) -> Result<usize, MapTideError> {
+        Ok(self.frequencies(ref_name, coord)?.iter().sum())
+    }
 
-type MapTide = HashMap<String, CoordinateMap>;
+    /// Return an iterator over the insertions following the reference position `pos` of `ref_name`, and their counts.
+    pub fn insertions_at(
+        &self,
+        ref_name: &str,
+        pos: usize,
+    ) -> Result<impl Iterator<Item = (Coordinate, [usize; 6])> + '_, MapTideError> {
+        let ins_map = self
+            .ins_maps
+            .get(ref_name)
+            .ok_or_else(|| MapTideError::KeyNotFound)?;
 
-type RefLengths = HashMap<String, usize>;
+        Ok(ins_map
+            .range(Coordinate(pos, 1)..Coordinate(pos + 1, 0))
+            .map(|(coord, row)| (*coord, *row)))
+    }
+
+    /// Check that `other` has the same references as the pileup, with the same lengths and counted positions.
+    fn check_compatible(&self, other: &Pileup) -> Result<(), MapTideError> {
+        if self.ref_names != other.ref_names {
+            return Err(MapTideError::ReferenceMismatch(other.ref_names.join(",")));
+        }
+
+        for ref_name in self.ref_names.iter() {
+            if self.ref_length(ref_name)? != other.ref_length(ref_name)?
+                || self.bounds(ref_name)? != other.bounds(ref_name)?
+            {
+                return Err(MapTideError::ReferenceMismatch(ref_name.to_owned()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add the counts of `other` to the pileup, position by position, including insertions.
+    ///
+    /// Both pileups must have the same references, in the same order, with the same lengths and counted positions. Nothing is added if they do not.
+    pub fn add(&mut self, other: &Pileup) -> Result<(), MapTideError> {
+        self.check_compatible(other)?;
+
+        for ref_name in other.ref_names.iter() {
+            let (ref_arr, _) = self
+                .ref_arrs
+                .get_mut(ref_name)
+                .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+            let (other_arr, _) = other
+                .ref_arrs
+                .get(ref_name)
+                .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+            ref_arr.add_counts(0, other_arr)?;
+
+            let ins_map = self
+                .ins_maps
+                .get_mut(ref_name)
+                .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+            let other_ins_map = other
+                .ins_maps
+                .get(ref_name)
+                .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+            for (coord, other_row) in other_ins_map.iter() {
+                let row = ins_map.entry(*coord).or_insert([0; 6]);
+                for (count, other_count) in row.iter_mut().zip(other_row.iter()) {
+                    *count = count
+                        .checked_add(*other_count)
+                        .ok_or_else(|| MapTideError::IntegerOverflow)?;
+                }
+            }
+        }
+
+        self.read_stats.add(&other.read_stats);
+
+        Ok(())
+    }
+
+    /// Merge `pileups` into a single pileup, by adding their counts position by position.
+    ///
+    /// The merged pileup has the options of the first pileup, which is the only one copied. At least one pileup must be given.
+    pub fn merge<'a, I>(pileups: I) -> Result<Pileup, MapTideError>
+    where
+        I: IntoIterator<Item = &'a Pileup>,
+    {
+        let mut pileups = pileups.into_iter();
+        let mut merged = pileups
+            .next()
+            .ok_or_else(|| MapTideError::NoPileups)?
+            .clone();
+
+        for pileup in pileups {
+            merged.add(pileup)?;
+        }
+
+        Ok(merged)
+    }
+
+    /// Return a new pileup containing only the counts within `region`.
+    ///
+    /// The region is clamped to the positions that were counted.
+    pub fn slice(&self, region: &Region) -> Result<Pileup, MapTideError> {
+        let ref_name = region.name();
+
+        let (ref_arr, offset) = self
+            .ref_arrs
+            .get(ref_name)
+            .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+        let ins_map = self
+            .ins_maps
+            .get(ref_name)
+            .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+        // Clamp the region to the counted positions
+        let (first, last) = self.bounds(ref_name)?;
+        let start = region
+            .interval()
+            .start()
+            .map_or(first, |x| x.get())
+            .max(first);
+        let end = region.interval().end().map_or(last, |x| x.get()).min(last);
+
+        // A region outside the counted positions gives an empty slice
+        let end = end.max(start - 1);
+
+        let mut slice_arr = Counts::new((end + 1).saturating_sub(start), ref_arr.options());
+        for (i, row) in ref_arr
+            .rows()
+            .enumerate()
+            .skip(start - offset - 1)
+            .take(slice_arr.len())
+        {
+            slice_arr.add_row(i + offset + 1 - start, &row)?;
+        }
+
+        let slice_ins_map: CoordinateMap = ins_map
+            .range(Coordinate(start, 0)..Coordinate(end + 1, 0))
+            .map(|(coord, row)| (*coord, *row))
+            .collect();
+
+        let mut ref_arrs = RefMap::new();
+        ref_arrs.insert(ref_name.to_owned(), (slice_arr, start - 1));
+
+        let mut ins_maps = InsMaps::new();
+        ins_maps.insert(ref_name.to_owned(), slice_ins_map);
+
+        let mut ref_lengths = RefLengths::new();
+        ref_lengths.insert(ref_name.to_owned(), self.ref_length(ref_name)?);
+
+        Ok(Pileup {
+            ref_names: vec![ref_name.to_owned()],
+            ref_arrs,
+            ins_maps,
+            ref_lengths,
+            options: self.options.clone(),
+            read_stats: self.read_stats.clone(),
+        })
+    }
+}
 
 /// Open the BAM file located at `bam_path` and return a reader.
-fn get_reader(
+///
+/// If `threads` is greater than one, BGZF blocks are decompressed in parallel by `threads` workers.
+fn get_reader(bam_path: String, threads: usize) -> Result<BamReader, MapTideError> {
+    let mut reader = open_reader(bam_path, threads)?;
+
+    // Read the SAM header
+    reader.read_header()?;
+
+    // Return the reader
+    Ok(reader)
+}
+
+/// Return a reader of the BAM file located at `bam_path`, and its parsed SAM header.
+fn get_reader_and_header(
     bam_path: String,
-) -> Result<noodles::bam::Reader<noodles::bgzf::Reader<File>>, MapTideError> {
+    threads: usize,
+) -> Result<(BamReader, Header), MapTideError> {
+    let mut reader = open_reader(bam_path, threads)?;
+
+    // Read and parse the SAM header
+    let header = reader
+        .read_header()?
+        .parse()
+        .map_err(|_| MapTideError::InvalidHeader)?;
+
+    Ok((reader, header))
+}
+
+/// Open the BAM file located at `bam_path`, decompressing it with `threads` workers.
+fn open_reader(bam_path: String, threads: usize) -> Result<BamReader, MapTideError> {
     // Open file
     let file = File::open(bam_path)?;
 
     // Create a reader from the file
-    let mut reader = noodles::bam::Reader::new(file);
-
-    // Read the SAM header
-    reader.read_header()?;
+    let reader = match NonZeroUsize::new(threads) {
+        Some(worker_count) if threads > 1 => noodles::bam::Reader::from(
+            noodles::bgzf::reader::Builder::default()
+                .set_worker_count(worker_count)
+                .build_with_reader(file),
+        ),
+        _ => noodles::bam::Reader::new(file),
+    };
 
-    // Return the reader
     Ok(reader)
 }
 
+/// Return an iterator over the records of `reader`.
+///
+/// If `threads` is greater than one, records are decoded on a separate thread in batches of `BATCH_SIZE`, while the previous batch is counted.
+fn read_records(
+    mut reader: BamReader,
+    threads: usize,
+) -> Box<dyn Iterator<Item = io::Result<Record>>> {
+    if threads <= 1 {
+        return Box::new(iter::from_fn(move || {
+            let mut record = Record::default();
+            match reader.read_record(&mut record) {
+                Ok(0) => None,
+                Ok(_) => Some(Ok(record)),
+                Err(e) => Some(Err(e)),
+            }
+        }));
+    }
+
+    // Bound the number of decoded batches waiting to be counted
+    let (sender, receiver) = mpsc::sync_channel(threads);
+
+    thread::spawn(move || loop {
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        let mut finished = false;
+
+        while batch.len() < BATCH_SIZE {
+            let mut record = Record::default();
+            match reader.read_record(&mut record) {
+                Ok(0) => {
+                    finished = true;
+                    break;
+                }
+                Ok(_) => batch.push(Ok(record)),
+                Err(e) => {
+                    batch.push(Err(e));
+                    finished = true;
+                    break;
+                }
+            }
+        }
+
+        // Stop if there are no more records, or the receiver has gone
+        if sender.send(batch).is_err() || finished {
+            break;
+        }
+    });
+
+    Box::new(receiver.into_iter().flatten())
+}
+
+/// Create the file located at `output_path` and return a writer, or return a writer to stdout if `output_path` is `None`.
+fn get_writer(output_path: Option<String>) -> Result<Box<dyn Write>, MapTideError> {
+    match output_path {
+        Some(path) => Ok(Box::new(File::create(path)?)),
+        None => Ok(Box::new(io::stdout())),
+    }
+}
+
+/// Return the index of `base` within each row, or `None` if `base` is `None`.
+pub fn get_base_index(base: Option<String>) -> Result<Option<usize>, MapTideError> {
+    match base {
+        Some(b) => Ok(Some(
+            BASES
+                .iter()
+                .position(|x| x.eq_ignore_ascii_case(&b))
+                .ok_or_else(|| MapTideError::InvalidBase)?,
+        )),
+        None => Ok(None),
+    }
+}
+
 /// Add the base from `seq` at `seq_pos` to `ref_arr`.
-fn count_ref_base(
-    ref_arr: &mut RefArr,
+fn count_ref_base<C: Counter>(
+    ref_arr: &mut C,
     seq: &Sequence,
     ref_pos: usize,
     offset: usize,
@@ -71,26 +529,11 @@ fn count_ref_base(
 ) -> Result<(), MapTideError> {
     // Match the base at the given seq_pos, and update the CoordinateMap
     match seq.get(seq_pos) {
-        Some(&Base::A) => {
-            ref_arr[ref_pos - offset - 1][0] += 1;
-            Ok(())
-        }
-        Some(&Base::C) => {
-            ref_arr[ref_pos - offset - 1][1] += 1;
-            Ok(())
-        }
-        Some(&Base::G) => {
-            ref_arr[ref_pos - offset - 1][2] += 1;
-            Ok(())
-        }
-        Some(&Base::T) => {
-            ref_arr[ref_pos - offset - 1][3] += 1;
-            Ok(())
-        }
-        Some(&Base::N) => {
-            ref_arr[ref_pos - offset - 1][5] += 1;
-            Ok(())
-        }
+        Some(&Base::A) => ref_arr.increment(ref_pos - offset - 1, 0),
+        Some(&Base::C) => ref_arr.increment(ref_pos - offset - 1, 1),
+        Some(&Base::G) => ref_arr.increment(ref_pos - offset - 1, 2),
+        Some(&Base::T) => ref_arr.increment(ref_pos - offset - 1, 3),
+        Some(&Base::N) => ref_arr.increment(ref_pos - offset - 1, 5),
         Some(_) => Err(MapTideError::InvalidBase),
         None => Err(MapTideError::KeyNotFound),
     }
@@ -105,54 +548,92 @@ fn count_ins_base(
     ins_pos: usize,
 ) -> Result<(), MapTideError> {
     // Match the base at the given seq_pos, and update the CoordinateMap
-    match seq.get(seq_pos) {
-        Some(&Base::A) => {
-            ins_map
-                .entry(Coordinate(ref_pos, ins_pos))
-                .or_insert_with(|| [0; 6])[0] += 1;
-            Ok(())
-        }
-        Some(&Base::C) => {
-            ins_map
-                .entry(Coordinate(ref_pos, ins_pos))
-                .or_insert_with(|| [0; 6])[1] += 1;
-            Ok(())
-        }
-        Some(&Base::G) => {
-            ins_map
-                .entry(Coordinate(ref_pos, ins_pos))
-                .or_insert_with(|| [0; 6])[2] += 1;
-            Ok(())
-        }
-        Some(&Base::T) => {
-            ins_map
-                .entry(Coordinate(ref_pos, ins_pos))
-                .or_insert_with(|| [0; 6])[3] += 1;
-            Ok(())
-        }
-        Some(&Base::N) => {
-            ins_map
-                .entry(Coordinate(ref_pos, ins_pos))
-                .or_insert_with(|| [0; 6])[5] += 1;
-            Ok(())
+    let base_index = match seq.get(seq_pos) {
+        Some(&Base::A) => 0,
+        Some(&Base::C) => 1,
+        Some(&Base::G) => 2,
+        Some(&Base::T) => 3,
+        Some(&Base::N) => N_INDEX,
+        Some(_) => return Err(MapTideError::InvalidBase),
+        None => return Err(MapTideError::KeyNotFound),
+    };
+
+    let count = &mut ins_map
+        .entry(Coordinate(ref_pos, ins_pos))
+        .or_insert_with(|| [0; 6])[base_index];
+    *count = count
+        .checked_add(1)
+        .ok_or_else(|| MapTideError::IntegerOverflow)?;
+    Ok(())
+}
+
+/// A single position visited while walking along the CIGAR information of a record.
+#[derive(Debug, Clone, Copy)]
+pub enum Step {
+    /// The base at `seq_pos` is aligned to `ref_pos`.
+    Match { ref_pos: usize, seq_pos: Position },
+
+    /// The base at `seq_pos` is the `ins_pos`-th base of an insertion before `ref_pos`.
+    Insertion {
+        ref_pos: usize,
+        seq_pos: Position,
+        ins_pos: usize,
+    },
+
+    /// `ref_pos` is deleted (or skipped, if `skip` is `true`), and the next base is at `seq_pos`.
+    Deletion {
+        ref_pos: usize,
+        seq_pos: Position,
+        skip: bool,
+    },
+}
+
+/// Return the sequence positions of the first and last aligned bases of `record`, after any soft clips.
+pub fn aligned_bounds(record: &Record) -> (usize, usize) {
+    let ops: Vec<_> = record
+        .cigar()
+        .iter()
+        .filter(|op| op.kind() != Kind::HardClip)
+        .collect();
+
+    let clipped = |op: Option<&&_>| match op {
+        Some(op) if op.kind() == Kind::SoftClip => op.len(),
+        _ => 0,
+    };
+
+    let start_clip: usize = clipped(ops.first());
+    let end_clip: usize = clipped(ops.last());
+    let len = record.sequence().len();
+
+    (start_clip + 1, len.saturating_sub(end_clip))
+}
+
+impl Step {
+    /// Return the coordinate of the step, which is shared by a match and a deletion at the same reference position.
+    pub fn coordinate(&self) -> Coordinate {
+        match *self {
+            Step::Match { ref_pos, .. } | Step::Deletion { ref_pos, .. } => Coordinate(ref_pos, 0),
+            Step::Insertion {
+                ref_pos, ins_pos, ..
+            } => Coordinate(ref_pos, ins_pos),
         }
-        Some(_) => Err(MapTideError::InvalidBase),
-        None => Err(MapTideError::KeyNotFound),
     }
 }
 
-/// Use the CIGAR information of `record` to count each base in its sequence, and add them to `ref_arr`, or `ins_map`.
+/// Walk along the CIGAR information of `record`, calling `visit` for each `Step` between `region_start` and `region_end`.
 ///
-/// Bases are ignored if their quality score is less than `base_quality`.
-fn count_record(
-    ref_arr: &mut RefArr,
-    offset: usize,
-    ins_map: &mut CoordinateMap,
+/// Bases are ignored if their quality score is less than `base_quality`, or if they are within `trim_ends` bases of either end of the aligned part of the record, after any soft clips. Deletions are ignored if either of their neighbouring bases is.
+pub fn walk_record<F>(
     record: &Record,
     base_quality: usize,
+    trim_ends: usize,
     region_start: usize,
     region_end: usize,
-) -> Result<(), MapTideError> {
+    mut visit: F,
+) -> Result<(), MapTideError>
+where
+    F: FnMut(Step) -> Result<(), MapTideError>,
+{
     // Positions are 1-based
     // This is the start position of the read in the reference
     let mut ref_pos = record
@@ -163,12 +644,15 @@ fn count_record(
     // This is the position locally along the sequence (minimum is 1)
     let mut seq_pos = Position::MIN;
 
-    // The read sequence
-    let seq = record.sequence();
-
     // The read sequence quality scores
     let quals = record.quality_scores();
 
+    // Positions along the sequence of the first and last bases that are not trimmed
+    let (first, last) = aligned_bounds(record);
+    let first = first + trim_ends;
+    let last = last.saturating_sub(trim_ends);
+    let untrimmed = |pos: usize| pos >= first && pos <= last;
+
     // Iterate through CIGAR information
     for cig in record.cigar().iter() {
         match cig.kind() {
@@ -177,9 +661,10 @@ fn count_record(
                 for _ in 1..=cig.len() {
                     if ref_pos >= region_start
                         && ref_pos <= region_end
+                        && untrimmed(seq_pos.get())
                         && min_base_quality(quals, seq_pos, base_quality)?
                     {
-                        count_ref_base(ref_arr, seq, ref_pos, offset, seq_pos)?;
+                        visit(Step::Match { ref_pos, seq_pos })?;
                     }
 
                     ref_pos += 1;
@@ -194,9 +679,14 @@ fn count_record(
                 for i in 1..=cig.len() {
                     if ref_pos >= region_start
                         && ref_pos <= region_end
+                        && untrimmed(seq_pos.get())
                         && min_base_quality(quals, seq_pos, base_quality)?
                     {
-                        count_ins_base(ins_map, seq, ref_pos, seq_pos, i)?;
+                        visit(Step::Insertion {
+                            ref_pos,
+                            seq_pos,
+                            ins_pos: i,
+                        })?;
                     }
 
                     seq_pos = seq_pos
@@ -208,8 +698,18 @@ fn count_record(
             // Deletion/skip consumes the reference only
             Kind::Deletion | Kind::Skip => {
                 for _ in 1..=cig.len() {
-                    if ref_pos >= region_start && ref_pos <= region_end {
-                        ref_arr[ref_pos - offset - 1][4] += 1;
+                    // When trimming, the bases either side of the deletion at `seq_pos - 1` and `seq_pos` must be untrimmed
+                    if ref_pos >= region_start
+                        && ref_pos <= region_end
+                        && (trim_ends == 0
+                            || (untrimmed(seq_pos.get().saturating_sub(1))
+                                && untrimmed(seq_pos.get())))
+                    {
+                        visit(Step::Deletion {
+                            ref_pos,
+                            seq_pos,
+                            skip: cig.kind() == Kind::Skip,
+                        })?;
                     }
 
                     ref_pos += 1;
@@ -230,8 +730,70 @@ fn count_record(
     Ok(())
 }
 
+/// Use the CIGAR information of `record` to count each base in its sequence, and add them to `ref_arr`, or `ins_map`.
+///
+/// Bases are ignored if their quality score is less than `base_quality`, or if they are within `trim_ends` bases of either end of the aligned part of the record.
+///
+/// Positions in `mask` overlap the mate of the record, and are skipped or counted as N.
+#[allow(clippy::too_many_arguments)]
+pub fn count_record<C: Counter>(
+    ref_arr: &mut C,
+    offset: usize,
+    ins_map: &mut CoordinateMap,
+    record: &Record,
+    mask: &OverlapMask,
+    base_quality: usize,
+    trim_ends: usize,
+    region_start: usize,
+    region_end: usize,
+) -> Result<(), MapTideError> {
+    // The read sequence
+    let seq = record.sequence();
+
+    walk_record(
+        record,
+        base_quality,
+        trim_ends,
+        region_start,
+        region_end,
+        |step| match (mask.get(&step.coordinate()), step) {
+            (Some(Resolution::Skip), _) => Ok(()),
+            (
+                Some(Resolution::Ambiguous),
+                Step::Insertion {
+                    ref_pos, ins_pos, ..
+                },
+            ) => {
+                let count = &mut ins_map
+                    .entry(Coordinate(ref_pos, ins_pos))
+                    .or_insert_with(|| [0; 6])[N_INDEX];
+                *count = count
+                    .checked_add(1)
+                    .ok_or_else(|| MapTideError::IntegerOverflow)?;
+                Ok(())
+            }
+            (Some(Resolution::Ambiguous), Step::Match { ref_pos, .. })
+            | (Some(Resolution::Ambiguous), Step::Deletion { ref_pos, .. }) => {
+                ref_arr.increment(ref_pos - offset - 1, N_INDEX)
+            }
+            (None, Step::Match { ref_pos, seq_pos }) => {
+                count_ref_base(ref_arr, seq, ref_pos, offset, seq_pos)
+            }
+            (
+                None,
+                Step::Insertion {
+                    ref_pos,
+                    seq_pos,
+                    ins_pos,
+                },
+            ) => count_ins_base(ins_map, seq, ref_pos, seq_pos, ins_pos),
+            (None, Step::Deletion { ref_pos, .. }) => ref_arr.increment(ref_pos - offset - 1, 4),
+        },
+    )
+}
+
 /// Check the interval defined by the alignment of `record` intersects the interval defined in `region`.
-fn intersects(record: &Record, region: &Region) -> Result<bool, MapTideError> {
+pub fn intersects(record: &Record, region: &Region) -> Result<bool, MapTideError> {
     let seq_start = record
         .alignment_start()
         .ok_or_else(|| MapTideError::AlignmentStartNotFound)?;
@@ -249,8 +811,29 @@ fn intersects(record: &Record, region: &Region) -> Result<bool, MapTideError> {
     }
 }
 
+/// Check that `record` is on the reference named in `region`, and intersects it.
+///
+/// Records without a reference or a position are not within any region.
+pub(crate) fn within_region(
+    record: &Record,
+    ref_seqs: &ReferenceSequences,
+    region: &Region,
+) -> Result<bool, MapTideError> {
+    let ref_name = match record.reference_sequence_id() {
+        Some(id) => {
+            ref_seqs
+                .get_index(id)
+                .ok_or_else(|| MapTideError::IndexNotFound)?
+                .0
+        }
+        None => return Ok(false),
+    };
+
+    Ok(ref_name == region.name() && intersects(record, region).unwrap_or(false))
+}
+
 /// Check the quality score for the base at `seq_pos` is greater than or equal to `base_quality`.
-fn min_base_quality(
+pub fn min_base_quality(
     quals: &QualityScores,
     seq_pos: Position,
     base_quality: usize,
@@ -270,7 +853,7 @@ fn min_base_quality(
 }
 
 /// Check the mapping score for `record` is greater than or equal to `mapping_quality`.
-fn min_mapping_quality(record: &Record, mapping_quality: usize) -> Result<bool, MapTideError> {
+pub fn min_mapping_quality(record: &Record, mapping_quality: usize) -> Result<bool, MapTideError> {
     let map_qual = usize::from(
         record
             .mapping_quality()
@@ -285,12 +868,23 @@ fn min_mapping_quality(record: &Record, mapping_quality: usize) -> Result<bool,
     }
 }
 
-fn init_maps() -> (RefMap, MapTide, RefLengths) {
+/// Return the flags of records that are excluded from the pileup by default.
+pub fn filter_flags() -> Flags {
+    Flags::from(
+        Flags::UNMAPPED.bits()
+            + Flags::SUPPLEMENTARY.bits()
+            + Flags::SECONDARY.bits()
+            + Flags::QC_FAIL.bits()
+            + Flags::DUPLICATE.bits(),
+    )
+}
+
+fn init_maps() -> (RefMap, InsMaps, RefLengths) {
     // Map of reference names to vector for storing base counts
     let ref_arrs: RefMap = RefMap::new();
 
     // Map of reference names to CoordinateMap, for storing insertion data
-    let ins_maps: MapTide = MapTide::new();
+    let ins_maps: InsMaps = InsMaps::new();
 
     // Map of reference names to reference lengths
     let ref_lengths: RefLengths = RefLengths::new();
@@ -305,9 +899,10 @@ fn init_maps() -> (RefMap, MapTide, RefLengths) {
 /// If `region` is `Some`, initialises array over the region specified.
 fn init_coordinates(
     ref_arrs: &mut RefMap,
-    ins_maps: &mut MapTide,
+    ins_maps: &mut InsMaps,
     ref_lengths: &RefLengths,
     region: Option<&Region>,
+    options: CountOptions,
 ) -> Result<(), MapTideError> {
     if let Some(reg) = region {
         let region_name = reg.name();
@@ -333,7 +928,7 @@ fn init_coordinates(
         // Add reference to ref_arrs and ins_maps
         ref_arrs.entry(region_name.to_owned()).or_insert_with(|| {
             (
-                vec![[0; 6]; region_end - region_start + 1],
+                Counts::new(region_end - region_start + 1, options),
                 region_start - 1,
             )
         });
@@ -346,7 +941,7 @@ fn init_coordinates(
         for (ref_name, ref_length) in ref_lengths.iter() {
             ref_arrs
                 .entry(ref_name.to_owned())
-                .or_insert_with(|| (vec![[0; 6]; *ref_length], 0));
+                .or_insert_with(|| (Counts::new(*ref_length, options), 0));
 
             ins_maps
                 .entry(ref_name.to_owned())
@@ -357,35 +952,29 @@ fn init_coordinates(
     Ok(())
 }
 
-/// Merge `ref_arrs` into `ins_maps` to have a single `MapTide` containing all coordinates and counts.
-fn merge_into_base_map(
-    ref_arrs: &RefMap,
-    mut ins_maps: MapTide,
-    ref_lengths: &RefLengths,
-) -> Result<MapTide, MapTideError> {
-    for (ref_name, _) in ref_lengths.iter() {
-        let (ref_arr, offset) = ref_arrs
-            .get(ref_name)
-            .ok_or_else(|| MapTideError::KeyNotFound)?;
-
-        let ins_map = ins_maps
-            .get_mut(ref_name)
-            .ok_or_else(|| MapTideError::KeyNotFound)?;
+/// Count the bases across all references of the BAM file located at `bam_path`.
+///
+/// If `bai_path` is `Some` and more than one thread is used, the references are split into windows of the window size in `options` (or whole references if it is `None`) which are counted in parallel.
+pub fn pileup_all(
+    bam_path: String,
+    bai_path: Option<String>,
+    options: &PileupOptions,
+) -> Result<Pileup, MapTideError> {
+    let base_quality = options.base_quality();
+    let trim_ends = options.trim_ends();
+    let threads = options.threads();
 
-        for (i, row) in ref_arr.iter().enumerate() {
-            ins_map.entry(Coordinate(i + offset + 1, 0)).or_insert(*row);
+    if let Some(b_path) = bai_path {
+        if threads > 1 {
+            return parallel::pileup_windows(bam_path, b_path, options);
         }
     }
-    Ok(ins_maps)
-}
 
-#[pyfunction]
-fn all(bam_path: String, mapping_quality: usize, base_quality: usize) -> PyResult<MapTide> {
     // Create initial maps
     let (mut ref_arrs, mut ins_maps, mut ref_lengths) = init_maps();
 
     // Reader for iterating through records
-    let mut reader = get_reader(bam_path)?;
+    let mut reader = get_reader(bam_path, threads)?;
 
     // Reference sequence information
     let ref_seqs = reader.read_reference_sequences()?;
@@ -396,24 +985,18 @@ fn all(bam_path: String, mapping_quality: usize, base_quality: usize) -> PyResul
     }
 
     // Initialise coordinates
-    init_coordinates(&mut ref_arrs, &mut ins_maps, &ref_lengths, None)?;
-
-    // Define flags for filtering records
-    let flags = Flags::from(
-        Flags::UNMAPPED.bits()
-            + Flags::SUPPLEMENTARY.bits()
-            + Flags::SECONDARY.bits()
-            + Flags::QC_FAIL.bits()
-            + Flags::DUPLICATE.bits(),
-    );
-
-    for result in reader.records() {
-        let record = result?;
-
-        if record.flags().intersects(flags) || !min_mapping_quality(&record, mapping_quality)? {
-            continue;
-        }
-
+    init_coordinates(
+        &mut ref_arrs,
+        &mut ins_maps,
+        &ref_lengths,
+        None,
+        options.count_options(),
+    )?;
+
+    let mut read_stats = ReadStats::default();
+    let mut overlaps = MateOverlaps::new(options.overlaps(), base_quality, trim_ends);
+
+    let mut count = |record: Record, mask: OverlapMask| -> Result<(), MapTideError> {
         let ref_seq_id = record
             .reference_sequence_id()
             .ok_or_else(|| MapTideError::ReferenceSequenceIDNotFound)?;
@@ -440,29 +1023,62 @@ fn all(bam_path: String, mapping_quality: usize, base_quality: usize) -> PyResul
             *offset,
             ins_map,
             &record,
+            &mask,
             base_quality,
+            trim_ends,
             1,
             *ref_length,
-        )?;
+        )
+    };
+
+    for result in read_records(reader, threads) {
+        let record = result?;
+
+        let reason = options.filter_reason(&record)?;
+        read_stats.add_record(&record, reason);
+
+        if reason.is_some() {
+            continue;
+        }
+
+        for (record, mask) in overlaps.push(record)? {
+            count(record, mask)?;
+        }
     }
 
-    let base_map = merge_into_base_map(&ref_arrs, ins_maps, &ref_lengths)?;
-    Ok(base_map)
+    // Count the first mates whose mate was never reached
+    for (record, mask) in overlaps.finish() {
+        count(record, mask)?;
+    }
+
+    Ok(Pileup {
+        ref_names: ref_seqs.keys().cloned().collect(),
+        ref_arrs,
+        ins_maps,
+        ref_lengths,
+        options: options.clone(),
+        read_stats,
+    })
 }
 
-#[pyfunction]
-fn query(
+/// Count the bases within `region` of the BAM file located at `bam_path`.
+///
+/// If `bai_path` is `Some`, the index is used to fetch only the records intersecting `region`.
+pub fn pileup_query(
     bam_path: String,
     bai_path: Option<String>,
-    region: String,
-    mapping_quality: usize,
-    base_quality: usize,
-) -> PyResult<MapTide> {
+    region: &Region,
+    options: &PileupOptions,
+) -> Result<Pileup, MapTideError> {
+    let base_quality = options.base_quality();
+    let trim_ends = options.trim_ends();
+    let threads = options.threads();
+
     // Create initial maps
     let (mut ref_arrs, mut ins_maps, mut ref_lengths) = init_maps();
 
     // Reader for iterating through records
-    let mut reader = get_reader(bam_path)?;
+    let mut reader = get_reader(bam_path, threads)?;
 
     // Reference sequence information
     let ref_seqs = reader.read_reference_sequences()?;
@@ -472,10 +1088,6 @@ fn query(
         ref_lengths.insert(reff.0.to_owned(), reff.1.length().get());
     }
 
-    // Parse region
-    let region: Region = region
-        .parse()
-        .map_err(|x: ParseError| PyException::new_err(x.to_string()))?;
     let region_name = region.name();
 
     // Handle unbounded region start
@@ -493,16 +1105,13 @@ fn query(
     };
 
     // Initialise coordinates
-    init_coordinates(&mut ref_arrs, &mut ins_maps, &ref_lengths, Some(&region))?;
-
-    // Define flags for filtering records
-    let flags = Flags::from(
-        Flags::UNMAPPED.bits()
-            + Flags::SUPPLEMENTARY.bits()
-            + Flags::SECONDARY.bits()
-            + Flags::QC_FAIL.bits()
-            + Flags::DUPLICATE.bits(),
-    );
+    init_coordinates(
+        &mut ref_arrs,
+        &mut ins_maps,
+        &ref_lengths,
+        Some(region),
+        options.count_options(),
+    )?;
 
     let (ref_arr, offset) = ref_arrs
         .get_mut(region_name)
@@ -512,89 +1121,156 @@ fn query(
         .get_mut(region_name)
         .ok_or_else(|| MapTideError::KeyNotFound)?;
 
+    let mut read_stats = ReadStats::default();
+    let mut overlaps = MateOverlaps::new(options.overlaps(), base_quality, trim_ends);
+
+    let mut count = |record: Record, mask: OverlapMask| {
+        count_record(
+            ref_arr,
+            *offset,
+            ins_map,
+            &record,
+            &mask,
+            base_quality,
+            trim_ends,
+            region_start,
+            region_end,
+        )
+    };
+
     if let Some(b_path) = bai_path {
         // Read the index file
         let index = bai::read(b_path)?;
 
         // Create query iterator over reads intersecting the region
-        let query = reader.query(&ref_seqs, &index, &region)?;
+        let query = reader.query(&ref_seqs, &index, region)?;
 
         for result in query {
             let record = result?;
-            if record.flags().intersects(flags) || !min_mapping_quality(&record, mapping_quality)? {
+
+            let reason = options.filter_reason(&record)?;
+            read_stats.add_record(&record, reason);
+
+            if reason.is_some() {
                 continue;
             }
 
-            count_record(
-                ref_arr,
-                *offset,
-                ins_map,
-                &record,
-                base_quality,
-                region_start,
-                region_end,
-            )?;
+            for (record, mask) in overlaps.push(record)? {
+                count(record, mask)?;
+            }
         }
     } else {
-        for result in reader.records() {
+        for result in read_records(reader, threads) {
             let record = result?;
-            let record_ref_name = ref_seqs
-                .get_index(
-                    record
-                        .reference_sequence_id()
-                        .ok_or_else(|| MapTideError::ReferenceSequenceIDNotFound)?,
-                )
-                .ok_or_else(|| MapTideError::IndexNotFound)?
-                .0;
 
-            if record.flags().intersects(flags)
-                || record_ref_name != region.name()
-                || !intersects(&record, &region)?
-                || !min_mapping_quality(&record, mapping_quality)?
-            {
+            // Records outside the region are skipped before filtering, as they may lack fields that the filters need
+            if !within_region(&record, &ref_seqs, region)? {
                 continue;
             }
 
-            count_record(
-                ref_arr,
-                *offset,
-                ins_map,
-                &record,
-                base_quality,
-                region_start,
-                region_end,
-            )?;
+            let reason = options.filter_reason(&record)?;
+            read_stats.add_record(&record, reason);
+
+            if reason.is_some() {
+                continue;
+            }
+
+            for (record, mask) in overlaps.push(record)? {
+                count(record, mask)?;
+            }
         }
     }
 
-    let base_map = merge_into_base_map(&ref_arrs, ins_maps, &ref_lengths)?;
-    Ok(base_map)
+    // Count the first mates whose mate was never reached
+    for (record, mask) in overlaps.finish() {
+        count(record, mask)?;
+    }
+
+    Ok(Pileup {
+        ref_names: vec![region_name.to_owned()],
+        ref_arrs,
+        ins_maps,
+        ref_lengths,
+        options: options.clone(),
+        read_stats,
+    })
 }
 
-#[pyfunction]
-fn parse_region(region: String) -> PyResult<(String, Option<usize>, Option<usize>)> {
-    let region: Region = region
-        .parse()
-        .map_err(|x: ParseError| PyException::new_err(x.to_string()))?;
-    let interval = region.interval();
-    let start = match interval.start() {
-        Some(x) => Some(x.get()),
-        None => None,
-    };
-    let end = match interval.end() {
-        Some(x) => Some(x.get()),
-        None => None,
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Return an empty pileup over a single reference named `chr` of `ref_length`.
+    fn empty_pileup(ref_length: usize) -> Pileup {
+        let (mut ref_arrs, mut ins_maps, mut ref_lengths) = init_maps();
+        ref_lengths.insert("chr".to_string(), ref_length);
+        init_coordinates(
+            &mut ref_arrs,
+            &mut ins_maps,
+            &ref_lengths,
+            None,
+            CountOptions::default(),
+        )
+        .unwrap();
+
+        Pileup {
+            ref_names: vec!["chr".to_string()],
+            ref_arrs,
+            ins_maps,
+            ref_lengths,
+            options: PileupOptions::default(),
+            read_stats: ReadStats::default(),
+        }
+    }
 
-    Ok((region.name().to_string(), start, end))
-}
+    /// Return a record aligned from `alignment_start` with `cigar`, and a sequence of `len` A bases.
+    fn record(alignment_start: usize, cigar: &str, len: usize) -> Record {
+        Record::builder()
+            .set_alignment_start(Position::new(alignment_start).unwrap())
+            .set_cigar(cigar.parse().unwrap())
+            .set_sequence("A".repeat(len).parse().unwrap())
+            .set_quality_scores("I".repeat(len).parse().unwrap())
+            .build()
+    }
 
-/// A Python module implemented in Rust.
-#[pymodule]
-fn maptide(_py: Python, m: &PyModule) -> PyResult<()> {
-    m.add_function(wrap_pyfunction!(all, m)?)?;
-    m.add_function(wrap_pyfunction!(query, m)?)?;
-    m.add_function(wrap_pyfunction!(parse_region, m)?)?;
+    #[test]
+    fn deletions_at_aligned_ends_are_counted_without_trimming() {
+        for (cigar, deletions) in [("2D50M", 0..2), ("50M2D", 50..52)] {
+            let mut ref_arr = vec![[0; 6]; 60];
+            count_record(
+                &mut ref_arr,
+                0,
+                &mut CoordinateMap::new(),
+                &record(1, cigar, 50),
+                &OverlapMask::new(),
+                0,
+                0,
+                1,
+                60,
+            )
+            .unwrap();
+
+            for (index, row) in ref_arr.iter().enumerate() {
+                let expected = if deletions.contains(&index) {
+                    [0, 0, 0, 0, 1, 0]
+                } else if index < 52 {
+                    [1, 0, 0, 0, 0, 0]
+                } else {
+                    [0; 6]
+                };
+                assert_eq!(*row, expected, "{} at index {}", cigar, index);
+            }
+        }
+    }
 
-    Ok(())
+    #[test]
+    fn slice_outside_bounds_is_empty() {
+        let pileup = empty_pileup(1000);
+        let inner = pileup.slice(&"chr:50-100".parse().unwrap()).unwrap();
+
+        for region in ["chr:500-600", "chr:1-10"] {
+            let slice = inner.slice(&region.parse().unwrap()).unwrap();
+            assert_eq!(slice.sorted_rows("chr").unwrap().count(), 0);
+        }
+    }
 }
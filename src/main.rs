@@ -0,0 +1,246 @@
+use clap::{ArgAction, Parser, ValueEnum};
+use noodles::core::Region;
+use std::error::Error;
+use std::path::Path;
+use std::process;
+
+use maptide::{
+    depth, get_base_index, mpileup, pileup_all, pileup_query, summary, tsv, CountOptions,
+    MapTideError, OverlapMode, Pileup, PileupOptions, PileupStream, TagFilter,
+};
+
+/// Output formats of the command-line tool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Tsv,
+    Bedgraph,
+    Depth,
+    Mpileup,
+}
+
+#[derive(Debug, Parser)]
+#[command(version, disable_version_flag = true)]
+struct Args {
+    /// Path to BAM file
+    bam: String,
+
+    /// Show program's version number and exit
+    #[arg(short = 'v', long, action = ArgAction::Version)]
+    version: Option<bool>,
+
+    /// Region to view, specified in the form CHROM:START-END (default: everything)
+    #[arg(short, long)]
+    region: Option<String>,
+
+    /// Path to index (BAI) file (default: </path/to/bam>.bai)
+    #[arg(short, long)]
+    index: Option<String>,
+
+    /// Minimum mapping quality
+    #[arg(short, long, default_value_t = 0)]
+    mapping_quality: usize,
+
+    /// Minimum base quality
+    #[arg(short, long, default_value_t = 0)]
+    base_quality: usize,
+
+    /// Number of bases to ignore at each end of the aligned part of a read, after any soft clips
+    #[arg(long, default_value_t = 0)]
+    trim_ends: usize,
+
+    /// Read groups to include, from the RG tag of each read (default: all reads)
+    #[arg(long, num_args = 1..)]
+    read_groups: Vec<String>,
+
+    /// Filter on the tags of each read, which may be given more than once: TAG, !TAG, TAG>N (or ==, !=, <, <=, >=), or TAG in A,B (or !in, with @FILE for one value per line)
+    #[arg(long)]
+    tag_filter: Vec<String>,
+
+    /// How the bases of a pair of mates are counted where the mates overlap (mpileup output only supports count): both, the higher quality base, or once if they agree and as an N if not
+    #[arg(long, default_value = "count", value_parser = ["count", "quality", "consensus"])]
+    overlaps: String,
+
+    /// Number of threads used to read the BAM file
+    #[arg(short, long, default_value_t = 1)]
+    threads: usize,
+
+    /// Output additional per-position statistics, including base and mapping qualities
+    #[arg(short, long)]
+    stats: bool,
+
+    /// For stats output, bases within this distance of the nearer aligned end of their read are counted as near the end
+    #[arg(long, default_value_t = 10)]
+    end_distance: usize,
+
+    /// Number of decimal places to display
+    #[arg(short, long, default_value_t = 3)]
+    decimals: usize,
+
+    /// Print a table of the coverage of each reference (or of the region) instead of the pileup
+    #[arg(long)]
+    summary: bool,
+
+    /// Print a histogram of the depth of each reference (or of the region) instead of the pileup
+    #[arg(long)]
+    histogram: bool,
+
+    /// For histogram output, depth of the final bucket, which also counts all greater depths
+    #[arg(long, default_value_t = 1000)]
+    histogram_cap: usize,
+
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = Format::Tsv)]
+    format: Format,
+
+    /// For bedgraph and depth output, report the depth of this base instead of the total depth (default: total)
+    #[arg(long, value_parser = ["A", "C", "G", "T", "DS", "N"])]
+    base: Option<String>,
+
+    /// For mpileup output, path to the reference FASTA file (default: reference bases are N)
+    #[arg(long)]
+    reference: Option<String>,
+
+    /// For bedgraph, depth, summary and histogram output, integer width of the counters stored at each position
+    #[arg(long, default_value_t = 64)]
+    width: usize,
+
+    /// For bedgraph, depth, summary and histogram output, storage of the counters of each reference
+    #[arg(long, default_value = "dense", value_parser = ["dense", "sparse", "auto"])]
+    storage: String,
+
+    /// For bedgraph, depth, summary and histogram output, stop counters at the maximum value of their width instead of failing
+    #[arg(long)]
+    saturate: bool,
+
+    /// Path to output file (default: stdout)
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// For tsv output, compress the output with BGZF, so that it can be indexed with tabix
+    #[arg(short = 'z', long)]
+    bgzip: bool,
+}
+
+/// Return `bai_path`, or the path of the BAM file with `.bai` appended if that file exists.
+fn find_index(bam_path: &str, bai_path: Option<String>) -> Option<String> {
+    bai_path.or_else(|| {
+        let default = format!("{}.bai", bam_path);
+        Path::new(&default).is_file().then_some(default)
+    })
+}
+
+/// Build the options of the pileup from the command-line arguments.
+fn pileup_options(args: &Args) -> Result<PileupOptions, MapTideError> {
+    let tag_filters = args
+        .tag_filter
+        .iter()
+        .map(|x| x.parse())
+        .collect::<Result<Vec<TagFilter>, MapTideError>>()?;
+
+    PileupOptions::builder()
+        .set_mapping_quality(args.mapping_quality)
+        .set_base_quality(args.base_quality)
+        .set_trim_ends(args.trim_ends)
+        .set_read_groups(args.read_groups.clone())
+        .set_tag_filters(tag_filters)
+        .set_overlaps(OverlapMode::from_name(&args.overlaps)?)
+        .set_threads(args.threads)
+        .set_count_options(CountOptions::new(args.width, &args.storage, args.saturate)?)
+        .set_qualities(args.stats)
+        .set_end_distance(args.end_distance)
+        .set_stats(args.stats)
+        .set_decimals(args.decimals)
+        .set_bgzip(args.bgzip)
+        .build()
+}
+
+/// Count the bases of the BAM file, over `region` if it is `Some`.
+fn pileup(
+    args: &Args,
+    region: Option<&Region>,
+    options: &PileupOptions,
+) -> Result<Pileup, MapTideError> {
+    let bai_path = find_index(&args.bam, args.index.clone());
+
+    match region {
+        Some(reg) => pileup_query(args.bam.clone(), bai_path, reg, options),
+        None => pileup_all(args.bam.clone(), bai_path, options),
+    }
+}
+
+fn run(args: Args) -> Result<(), MapTideError> {
+    let region: Option<Region> = match &args.region {
+        Some(reg) => Some(reg.parse()?),
+        None => None,
+    };
+
+    let options = pileup_options(&args)?;
+
+    // Only tsv output can be compressed
+    if args.bgzip && (args.summary || args.histogram || args.format != Format::Tsv) {
+        return Err(MapTideError::InvalidOption("bgzip".to_string()));
+    }
+
+    if args.summary {
+        let pileup = pileup(&args, region.as_ref(), &options)?;
+        return summary::write_summary(&pileup, args.output, args.decimals);
+    }
+
+    if args.histogram {
+        let pileup = pileup(&args, region.as_ref(), &options)?;
+        return summary::write_histogram(&pileup, args.output, args.histogram_cap);
+    }
+
+    match args.format {
+        Format::Bedgraph => {
+            let base_index = get_base_index(args.base.clone())?;
+            let pileup = pileup(&args, region.as_ref(), &options)?;
+            depth::write_bedgraph(&pileup, args.output, base_index)
+        }
+        Format::Depth => {
+            let base_index = get_base_index(args.base.clone())?;
+            let pileup = pileup(&args, region.as_ref(), &options)?;
+            depth::write_depth(&pileup, args.output, base_index)
+        }
+        Format::Mpileup => {
+            let bai_path = match region {
+                Some(_) => find_index(&args.bam, args.index),
+                None => None,
+            };
+
+            mpileup::write_mpileup(
+                args.bam,
+                bai_path,
+                region.as_ref(),
+                args.output,
+                args.reference,
+                &options,
+            )
+        }
+        Format::Tsv => {
+            let stream = match region {
+                Some(reg) => PileupStream::query(
+                    args.bam.clone(),
+                    find_index(&args.bam, args.index),
+                    reg,
+                    &options,
+                )?,
+                None => PileupStream::all(args.bam, &options)?,
+            };
+
+            tsv::write_tsv(stream.with_qualities(), args.output, &options)
+        }
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+
+    if let Err(e) = run(args) {
+        match e.source() {
+            Some(source) => eprintln!("Error: {}: {}", e, source),
+            None => eprintln!("Error: {}", e),
+        }
+        process::exit(1);
+    }
+}
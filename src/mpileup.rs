@@ -0,0 +1,360 @@
+use noodles::bam::bai;
+use noodles::core::Region;
+use noodles::fasta;
+use noodles::sam::alignment::Record;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+
+use crate::error::MapTideError;
+use crate::options::PileupOptions;
+use crate::{get_reader, get_writer, read_records, walk_record, within_region, OverlapMode, Step};
+
+/// The reads covering a single reference position, in `samtools mpileup` notation.
+#[derive(Default)]
+struct Column {
+    depth: usize,
+    bases: String,
+    quals: String,
+}
+
+/// Read the sequences of the FASTA file located at `reference_path`.
+fn read_reference(reference_path: String) -> Result<HashMap<String, Vec<u8>>, MapTideError> {
+    let mut reader = fasta::Reader::new(BufReader::new(File::open(reference_path)?));
+    let mut ref_seqs = HashMap::new();
+
+    for result in reader.records() {
+        let record = result?;
+        ref_seqs.insert(
+            record.name().to_owned(),
+            record.sequence().as_ref().to_vec(),
+        );
+    }
+
+    Ok(ref_seqs)
+}
+
+/// Return the reference base at `ref_pos`, or `N` if the reference sequence is unknown.
+fn ref_base(ref_seq: Option<&Vec<u8>>, ref_pos: usize) -> char {
+    ref_seq
+        .and_then(|x| x.get(ref_pos - 1))
+        .map(|x| x.to_ascii_uppercase() as char)
+        .unwrap_or('N')
+}
+
+/// Convert a quality score into its Phred+33 character.
+fn qual_char(qual: u8) -> char {
+    (qual.min(93) + 33) as char
+}
+
+/// Return `base` in the case of the strand it was read from.
+fn stranded(base: char, reverse: bool) -> char {
+    if reverse {
+        base.to_ascii_lowercase()
+    } else {
+        base.to_ascii_uppercase()
+    }
+}
+
+/// Use the CIGAR information of `record` to add its bases to `columns`.
+///
+/// Bases are ignored if their quality score is less than `base_quality`, or if they are within `trim_ends` bases of either end of the aligned part of the record.
+#[allow(clippy::too_many_arguments)]
+fn add_record(
+    columns: &mut BTreeMap<usize, Column>,
+    ref_seq: Option<&Vec<u8>>,
+    record: &Record,
+    base_quality: usize,
+    trim_ends: usize,
+    region_start: usize,
+    region_end: usize,
+) -> Result<(), MapTideError> {
+    let seq = record.sequence();
+    let quals = record.quality_scores();
+    let reverse = record.flags().is_reverse_complemented();
+
+    let alignment_start = record
+        .alignment_start()
+        .ok_or_else(|| MapTideError::AlignmentStartNotFound)?
+        .get();
+
+    let alignment_end = record
+        .alignment_end()
+        .ok_or_else(|| MapTideError::AlignmentEndNotFound)?
+        .get();
+
+    let mapping_quality = record
+        .mapping_quality()
+        .ok_or_else(|| MapTideError::MappingQualityNotFound)?
+        .get();
+
+    // Reference position of the last base added to columns, which indels are appended to
+    let mut last_pos: Option<usize> = None;
+
+    // Bases of the current insertion, and reference positions of the current deletion
+    let mut insertion = String::new();
+    let mut deletion: Vec<usize> = Vec::new();
+
+    // Append the current insertion or deletion to the column of the last base
+    let flush_indel = |columns: &mut BTreeMap<usize, Column>,
+                       last_pos: Option<usize>,
+                       insertion: &mut String,
+                       deletion: &mut Vec<usize>| {
+        if let Some(column) = last_pos.and_then(|pos| columns.get_mut(&pos)) {
+            if !insertion.is_empty() {
+                column.bases += &format!("+{}{}", insertion.len(), insertion);
+            }
+            if !deletion.is_empty() {
+                let ref_bases: String = deletion
+                    .iter()
+                    .map(|&pos| stranded(ref_base(ref_seq, pos), reverse))
+                    .collect();
+                column.bases += &format!("-{}{}", deletion.len(), ref_bases);
+            }
+        }
+        insertion.clear();
+        deletion.clear();
+    };
+
+    walk_record(
+        record,
+        base_quality,
+        trim_ends,
+        region_start,
+        region_end,
+        |step| {
+            match step {
+                Step::Match { ref_pos, seq_pos } => {
+                    flush_indel(columns, last_pos, &mut insertion, &mut deletion);
+
+                    let base = char::from(
+                        seq.get(seq_pos)
+                            .copied()
+                            .ok_or_else(|| MapTideError::KeyNotFound)?,
+                    );
+                    let qual = quals
+                        .get(seq_pos)
+                        .ok_or_else(|| MapTideError::QualityScoreNotFound)?
+                        .get();
+
+                    let column = columns.entry(ref_pos).or_default();
+                    if ref_pos == alignment_start {
+                        column.bases.push('^');
+                        column.bases.push(qual_char(mapping_quality));
+                    }
+                    if base.to_ascii_uppercase() == ref_base(ref_seq, ref_pos) {
+                        column.bases.push(if reverse { ',' } else { '.' });
+                    } else {
+                        column.bases.push(stranded(base, reverse));
+                    }
+                    if ref_pos == alignment_end {
+                        column.bases.push('$');
+                    }
+                    column.quals.push(qual_char(qual));
+                    column.depth += 1;
+
+                    last_pos = Some(ref_pos);
+                }
+                Step::Insertion { seq_pos, .. } => {
+                    let base = char::from(
+                        seq.get(seq_pos)
+                            .copied()
+                            .ok_or_else(|| MapTideError::KeyNotFound)?,
+                    );
+                    insertion.push(stranded(base, reverse));
+                }
+                Step::Deletion {
+                    ref_pos,
+                    seq_pos,
+                    skip,
+                } => {
+                    // Deletions are appended to the last base before them, but skips are not
+                    if skip {
+                        flush_indel(columns, last_pos, &mut insertion, &mut deletion);
+                    } else {
+                        deletion.push(ref_pos);
+                    }
+
+                    let qual = quals.get(seq_pos).map(|x| x.get()).unwrap_or(0);
+
+                    let column = columns.entry(ref_pos).or_default();
+                    column.bases.push(match (skip, reverse) {
+                        (true, false) => '>',
+                        (true, true) => '<',
+                        (false, _) => '*',
+                    });
+                    column.quals.push(qual_char(qual));
+                    column.depth += 1;
+                }
+            }
+            Ok(())
+        },
+    )?;
+
+    flush_indel(columns, last_pos, &mut insertion, &mut deletion);
+    Ok(())
+}
+
+/// Write and remove each column in `columns` before `ref_pos`, or every column if `ref_pos` is `None`.
+fn write_columns<W: Write>(
+    writer: &mut W,
+    columns: &mut BTreeMap<usize, Column>,
+    ref_name: &str,
+    ref_seq: Option<&Vec<u8>>,
+    ref_pos: Option<usize>,
+) -> Result<(), MapTideError> {
+    let remaining = match ref_pos {
+        Some(pos) => columns.split_off(&pos),
+        None => BTreeMap::new(),
+    };
+
+    for (pos, column) in columns.iter() {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            ref_name,
+            pos,
+            ref_base(ref_seq, *pos),
+            column.depth,
+            column.bases,
+            column.quals
+        )?;
+    }
+
+    *columns = remaining;
+    Ok(())
+}
+
+/// Write the reads covering each position of the BAM file located at `bam_path` in the format of `samtools mpileup`.
+///
+/// If `region` is `Some`, only positions within the region are written, and the index located at `bai_path` is used if provided.
+///
+/// If `reference_path` is `Some`, bases matching the reference are written as `.` and `,`. Otherwise, the reference base is `N`.
+///
+/// Records in the BAM file must be sorted by coordinate. Every base of each read is written, so overlapping mates can only be counted as they are.
+pub fn write_mpileup(
+    bam_path: String,
+    bai_path: Option<String>,
+    region: Option<&Region>,
+    output_path: Option<String>,
+    reference_path: Option<String>,
+    options: &PileupOptions,
+) -> Result<(), MapTideError> {
+    if options.overlaps() != OverlapMode::Count {
+        return Err(MapTideError::InvalidOption("overlaps".to_string()));
+    }
+
+    // Reader for iterating through records
+    let mut reader = get_reader(bam_path, options.threads())?;
+
+    // Reference sequence information
+    let ref_seqs = reader.read_reference_sequences()?;
+
+    // Reference sequences for comparing bases against
+    let references = match reference_path {
+        Some(path) => read_reference(path)?,
+        None => HashMap::new(),
+    };
+
+    let mut writer = BufWriter::new(get_writer(output_path)?);
+
+    // Columns that may still be covered by upcoming records, and the reference they belong to
+    let mut columns: BTreeMap<usize, Column> = BTreeMap::new();
+    let mut current_ref: Option<String> = None;
+
+    let mut process = |record: Record| -> Result<(), MapTideError> {
+        // Records outside the region are skipped before filtering, as they may lack fields that the filters need
+        if let Some(reg) = region {
+            if !within_region(&record, &ref_seqs, reg)? {
+                return Ok(());
+            }
+        }
+
+        if !options.filter(&record)? {
+            return Ok(());
+        }
+
+        let (ref_name, ref_map) = ref_seqs
+            .get_index(
+                record
+                    .reference_sequence_id()
+                    .ok_or_else(|| MapTideError::ReferenceSequenceIDNotFound)?,
+            )
+            .ok_or_else(|| MapTideError::IndexNotFound)?;
+
+        let (region_start, region_end) = match region {
+            Some(reg) => (
+                reg.interval().start().map(|x| x.get()).unwrap_or(1),
+                reg.interval()
+                    .end()
+                    .map(|x| x.get())
+                    .unwrap_or_else(|| ref_map.length().get()),
+            ),
+            None => (1, ref_map.length().get()),
+        };
+
+        let alignment_start = record
+            .alignment_start()
+            .ok_or_else(|| MapTideError::AlignmentStartNotFound)?
+            .get();
+
+        // Write the columns that no upcoming record can cover
+        match current_ref.as_deref() {
+            Some(name) if name == ref_name => {
+                write_columns(
+                    &mut writer,
+                    &mut columns,
+                    name,
+                    references.get(name),
+                    Some(alignment_start),
+                )?;
+            }
+            Some(name) => {
+                write_columns(&mut writer, &mut columns, name, references.get(name), None)?;
+                current_ref = Some(ref_name.to_owned());
+            }
+            None => current_ref = Some(ref_name.to_owned()),
+        }
+
+        add_record(
+            &mut columns,
+            references.get(ref_name),
+            &record,
+            options.base_quality(),
+            options.trim_ends(),
+            region_start,
+            region_end,
+        )
+    };
+
+    match (region, bai_path) {
+        (Some(reg), Some(b_path)) => {
+            // Read the index file
+            let index = bai::read(b_path)?;
+
+            // Create query iterator over reads intersecting the region
+            for result in reader.query(&ref_seqs, &index, reg)? {
+                process(result?)?;
+            }
+        }
+        _ => {
+            for result in read_records(reader, options.threads()) {
+                process(result?)?;
+            }
+        }
+    }
+
+    // Write the remaining columns
+    if let Some(name) = current_ref {
+        write_columns(
+            &mut writer,
+            &mut columns,
+            &name,
+            references.get(&name),
+            None,
+        )?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,389 @@
+use noodles::sam::alignment::Record;
+use noodles::sam::record::Flags;
+use serde::{Deserialize, Serialize};
+
+use crate::counts::CountOptions;
+use crate::error::MapTideError;
+use crate::groups::read_group;
+use crate::overlap::OverlapMode;
+use crate::tags::TagFilter;
+use crate::{filter_flags, min_mapping_quality};
+
+/// Reason that a record is filtered out of a pileup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterReason {
+    /// The record has an excluded flag, which is the single bit given.
+    ExcludedFlag(u16),
+
+    /// The record is missing a required flag.
+    RequiredFlags,
+
+    /// The sequence of the record is shorter than the minimum read length.
+    ReadLength,
+
+    /// The mapping quality of the record is below the minimum.
+    MappingQuality,
+
+    /// The record does not belong to one of the included read groups.
+    ReadGroup,
+
+    /// The record fails one of the tag filters.
+    Tag,
+}
+
+/// Highest number of decimal places that statistics can be written with.
+const MAX_DECIMALS: usize = 17;
+
+/// Options controlling how records are filtered and counted in a pileup, and how it is written.
+///
+/// Options can be written to and read from JSON or TOML, so that a pileup can be reproduced.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PileupOptions {
+    mapping_quality: usize,
+    base_quality: usize,
+    trim_ends: usize,
+    overlaps: OverlapMode,
+    required_flags: u16,
+    excluded_flags: u16,
+    min_read_length: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    read_groups: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tag_filters: Vec<TagFilter>,
+    threads: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    window_size: Option<usize>,
+    #[serde(flatten)]
+    count_options: CountOptions,
+    qualities: bool,
+    end_distance: usize,
+    stats: bool,
+    decimals: usize,
+    bgzip: bool,
+}
+
+impl PileupOptions {
+    /// Return a builder for the options, starting from the defaults.
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// Minimum mapping quality for a record to be counted.
+    pub fn mapping_quality(&self) -> usize {
+        self.mapping_quality
+    }
+
+    /// Minimum quality score for a base to be counted.
+    pub fn base_quality(&self) -> usize {
+        self.base_quality
+    }
+
+    /// Number of bases ignored at each end of the aligned part of a record, after any soft clips.
+    pub fn trim_ends(&self) -> usize {
+        self.trim_ends
+    }
+
+    /// How the bases of a pair of mates are counted where the mates overlap.
+    pub fn overlaps(&self) -> OverlapMode {
+        self.overlaps
+    }
+
+    /// Flags that a record must have all of to be counted.
+    pub fn required_flags(&self) -> Flags {
+        Flags::from(self.required_flags)
+    }
+
+    /// Flags that a record must have none of to be counted.
+    pub fn excluded_flags(&self) -> Flags {
+        Flags::from(self.excluded_flags)
+    }
+
+    /// Minimum length of the sequence of a record for it to be counted.
+    pub fn min_read_length(&self) -> usize {
+        self.min_read_length
+    }
+
+    /// Read groups that a record must belong to to be counted, or all read groups if empty.
+    pub fn read_groups(&self) -> &[String] {
+        &self.read_groups
+    }
+
+    /// Filters on the tags of a record, which it must pass all of to be counted.
+    pub fn tag_filters(&self) -> &[TagFilter] {
+        &self.tag_filters
+    }
+
+    /// Number of threads used to read the BAM file.
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Size of the windows that each reference is split into when counting in parallel, or `None` for whole references.
+    pub fn window_size(&self) -> Option<usize> {
+        self.window_size
+    }
+
+    /// Options for storing the counts of each reference.
+    pub fn count_options(&self) -> CountOptions {
+        self.count_options
+    }
+
+    /// Whether to collect the base and mapping qualities at each position, when streaming rows.
+    pub fn qualities(&self) -> bool {
+        self.qualities
+    }
+
+    /// Distance from the nearer aligned end of a read that a base is counted as near the end, when collecting qualities.
+    pub fn end_distance(&self) -> usize {
+        self.end_distance
+    }
+
+    /// Whether to write additional per-position statistics.
+    pub fn stats(&self) -> bool {
+        self.stats
+    }
+
+    /// Number of decimal places to write statistics with.
+    pub fn decimals(&self) -> usize {
+        self.decimals
+    }
+
+    /// Whether to compress written output with BGZF.
+    pub fn bgzip(&self) -> bool {
+        self.bgzip
+    }
+
+    /// Check that the options are consistent with each other, and within range.
+    pub fn validate(&self) -> Result<(), MapTideError> {
+        if self.mapping_quality > usize::from(u8::MAX) {
+            return Err(MapTideError::InvalidOption("mapping_quality".to_string()));
+        }
+
+        if self.required_flags & self.excluded_flags != 0 {
+            return Err(MapTideError::InvalidOption("required_flags".to_string()));
+        }
+
+        if self.threads == 0 {
+            return Err(MapTideError::InvalidOption("threads".to_string()));
+        }
+
+        if self.window_size == Some(0) {
+            return Err(MapTideError::InvalidOption("window_size".to_string()));
+        }
+
+        if self.decimals > MAX_DECIMALS {
+            return Err(MapTideError::InvalidOption("decimals".to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Check that `record` has the required flags, none of the excluded flags, and passes the mapping quality, read, read group and tag filters.
+    pub fn filter(&self, record: &Record) -> Result<bool, MapTideError> {
+        Ok(self.filter_reason(record)?.is_none())
+    }
+
+    /// Return the reason that `record` is filtered out of the pileup, or `None` if it passes the filters.
+    ///
+    /// Excluded flags are checked first, starting from the lowest bit, followed by required flags, read length, mapping quality, read group and tags.
+    pub fn filter_reason(&self, record: &Record) -> Result<Option<FilterReason>, MapTideError> {
+        let flags = record.flags().bits();
+
+        let excluded = flags & self.excluded_flags;
+        if excluded != 0 {
+            // Lowest excluded bit of the record
+            return Ok(Some(FilterReason::ExcludedFlag(
+                excluded & excluded.wrapping_neg(),
+            )));
+        }
+
+        if flags & self.required_flags != self.required_flags {
+            return Ok(Some(FilterReason::RequiredFlags));
+        }
+
+        if record.sequence().len() < self.min_read_length {
+            return Ok(Some(FilterReason::ReadLength));
+        }
+
+        if !min_mapping_quality(record, self.mapping_quality)? {
+            return Ok(Some(FilterReason::MappingQuality));
+        }
+
+        if !self.read_groups.is_empty()
+            && !read_group(record).map_or(false, |id| self.read_groups.iter().any(|x| x == id))
+        {
+            return Ok(Some(FilterReason::ReadGroup));
+        }
+
+        if !self.tag_filters.iter().all(|x| x.matches(record)) {
+            return Ok(Some(FilterReason::Tag));
+        }
+
+        Ok(None)
+    }
+
+    /// Write the options as JSON.
+    pub fn to_json(&self) -> Result<String, MapTideError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Read options from JSON, which are validated before being returned.
+    pub fn from_json(s: &str) -> Result<Self, MapTideError> {
+        let options: Self = serde_json::from_str(s)?;
+        options.validate()?;
+        Ok(options)
+    }
+
+    /// Write the options as TOML.
+    pub fn to_toml(&self) -> Result<String, MapTideError> {
+        Ok(toml::to_string(self)?)
+    }
+
+    /// Read options from TOML, which are validated before being returned.
+    pub fn from_toml(s: &str) -> Result<Self, MapTideError> {
+        let options: Self = toml::from_str(s)?;
+        options.validate()?;
+        Ok(options)
+    }
+}
+
+impl Default for PileupOptions {
+    fn default() -> Self {
+        Self {
+            mapping_quality: 0,
+            base_quality: 0,
+            trim_ends: 0,
+            overlaps: OverlapMode::Count,
+            required_flags: 0,
+            excluded_flags: filter_flags().bits(),
+            min_read_length: 0,
+            read_groups: Vec::new(),
+            tag_filters: Vec::new(),
+            threads: 1,
+            window_size: None,
+            count_options: CountOptions::default(),
+            qualities: false,
+            end_distance: 10,
+            stats: false,
+            decimals: 3,
+            bgzip: false,
+        }
+    }
+}
+
+/// A builder for `PileupOptions`.
+#[derive(Debug, Default)]
+pub struct Builder {
+    options: PileupOptions,
+}
+
+impl Builder {
+    /// Set the minimum mapping quality for a record to be counted.
+    pub fn set_mapping_quality(mut self, mapping_quality: usize) -> Self {
+        self.options.mapping_quality = mapping_quality;
+        self
+    }
+
+    /// Set the minimum quality score for a base to be counted.
+    pub fn set_base_quality(mut self, base_quality: usize) -> Self {
+        self.options.base_quality = base_quality;
+        self
+    }
+
+    /// Set the number of bases ignored at each end of the aligned part of a record, after any soft clips.
+    pub fn set_trim_ends(mut self, trim_ends: usize) -> Self {
+        self.options.trim_ends = trim_ends;
+        self
+    }
+
+    /// Set how the bases of a pair of mates are counted where the mates overlap.
+    pub fn set_overlaps(mut self, overlaps: OverlapMode) -> Self {
+        self.options.overlaps = overlaps;
+        self
+    }
+
+    /// Set the flags that a record must have all of to be counted.
+    pub fn set_required_flags(mut self, required_flags: Flags) -> Self {
+        self.options.required_flags = required_flags.bits();
+        self
+    }
+
+    /// Set the flags that a record must have none of to be counted.
+    pub fn set_excluded_flags(mut self, excluded_flags: Flags) -> Self {
+        self.options.excluded_flags = excluded_flags.bits();
+        self
+    }
+
+    /// Set the minimum length of the sequence of a record for it to be counted.
+    pub fn set_min_read_length(mut self, min_read_length: usize) -> Self {
+        self.options.min_read_length = min_read_length;
+        self
+    }
+
+    /// Set the read groups that a record must belong to to be counted, or all read groups if empty.
+    pub fn set_read_groups(mut self, read_groups: Vec<String>) -> Self {
+        self.options.read_groups = read_groups;
+        self
+    }
+
+    /// Set the filters on the tags of a record, which it must pass all of to be counted.
+    pub fn set_tag_filters(mut self, tag_filters: Vec<TagFilter>) -> Self {
+        self.options.tag_filters = tag_filters;
+        self
+    }
+
+    /// Set the number of threads used to read the BAM file.
+    pub fn set_threads(mut self, threads: usize) -> Self {
+        self.options.threads = threads;
+        self
+    }
+
+    /// Set the size of the windows that each reference is split into when counting in parallel.
+    pub fn set_window_size(mut self, window_size: Option<usize>) -> Self {
+        self.options.window_size = window_size;
+        self
+    }
+
+    /// Set the options for storing the counts of each reference.
+    pub fn set_count_options(mut self, count_options: CountOptions) -> Self {
+        self.options.count_options = count_options;
+        self
+    }
+
+    /// Set whether to collect the base and mapping qualities at each position, when streaming rows.
+    pub fn set_qualities(mut self, qualities: bool) -> Self {
+        self.options.qualities = qualities;
+        self
+    }
+
+    /// Set the distance from the nearer aligned end of a read that a base is counted as near the end, when collecting qualities.
+    pub fn set_end_distance(mut self, end_distance: usize) -> Self {
+        self.options.end_distance = end_distance;
+        self
+    }
+
+    /// Set whether to write additional per-position statistics.
+    pub fn set_stats(mut self, stats: bool) -> Self {
+        self.options.stats = stats;
+        self
+    }
+
+    /// Set the number of decimal places to write statistics with.
+    pub fn set_decimals(mut self, decimals: usize) -> Self {
+        self.options.decimals = decimals;
+        self
+    }
+
+    /// Set whether to compress written output with BGZF.
+    pub fn set_bgzip(mut self, bgzip: bool) -> Self {
+        self.options.bgzip = bgzip;
+        self
+    }
+
+    /// Validate and build the options.
+    pub fn build(self) -> Result<PileupOptions, MapTideError> {
+        self.options.validate()?;
+        Ok(self.options)
+    }
+}
@@ -0,0 +1,257 @@
+use noodles::sam::alignment::Record;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::mem;
+
+use crate::error::MapTideError;
+use crate::quality::get_base;
+use crate::{walk_record, Coordinate, Step, DELETION_INDEX};
+
+/// How the bases of a pair of mates are counted where the mates overlap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverlapMode {
+    /// Count the bases of both mates.
+    #[default]
+    Count,
+
+    /// Count only the base with the higher quality, or the base of the first mate if they are equal.
+    Quality,
+
+    /// Count the base once if both mates agree, or as an N if they do not.
+    Consensus,
+}
+
+impl OverlapMode {
+    /// Return the mode named `name`, which is one of `count`, `quality` or `consensus`.
+    pub fn from_name(name: &str) -> Result<Self, MapTideError> {
+        match name.to_ascii_lowercase().as_str() {
+            "count" => Ok(OverlapMode::Count),
+            "quality" => Ok(OverlapMode::Quality),
+            "consensus" => Ok(OverlapMode::Consensus),
+            _ => Err(MapTideError::InvalidOption("overlaps".to_string())),
+        }
+    }
+
+    /// Return the name of the mode.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OverlapMode::Count => "count",
+            OverlapMode::Quality => "quality",
+            OverlapMode::Consensus => "consensus",
+        }
+    }
+}
+
+/// How a base of a record is counted, where it overlaps its mate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// The base is not counted, as the base of its mate is counted instead.
+    Skip,
+
+    /// The base disagrees with the base of its mate, and is counted as an N.
+    Ambiguous,
+}
+
+/// Positions of a record that are counted differently because they overlap its mate.
+pub type OverlapMask = BTreeMap<Coordinate, Resolution>;
+
+/// Records that are ready to be counted, each with the positions that overlap its mate.
+pub type Ready = Vec<(Record, OverlapMask)>;
+
+/// Pairs up overlapping mates from records sorted by coordinate, so that each base of the overlap is counted once.
+///
+/// Only the first mate of an overlapping pair is held back, until its mate is reached or every record that could be its mate has been passed. Memory is therefore bounded by the number of pairs spanning a position.
+pub struct MateOverlaps {
+    mode: OverlapMode,
+    base_quality: usize,
+    trim_ends: usize,
+
+    // First mates waiting for their mate, keyed by reference, the start of their mate and name
+    pending: BTreeMap<(usize, usize, String), Record>,
+
+    // Number of pending first mates at each alignment start
+    starts: BTreeMap<usize, usize>,
+}
+
+impl MateOverlaps {
+    /// Create a buffer that resolves overlaps with `mode`, comparing the bases visited with `base_quality` and `trim_ends`.
+    pub fn new(mode: OverlapMode, base_quality: usize, trim_ends: usize) -> Self {
+        Self {
+            mode,
+            base_quality,
+            trim_ends,
+            pending: BTreeMap::new(),
+            starts: BTreeMap::new(),
+        }
+    }
+
+    /// Add the next `record`, returning the records that are now ready to be counted.
+    ///
+    /// Records must be added in coordinate order.
+    pub fn push(&mut self, record: Record) -> Result<Ready, MapTideError> {
+        if self.mode == OverlapMode::Count {
+            return Ok(vec![(record, OverlapMask::new())]);
+        }
+
+        let ref_id = record
+            .reference_sequence_id()
+            .ok_or_else(|| MapTideError::ReferenceSequenceIDNotFound)?;
+
+        let start = record
+            .alignment_start()
+            .ok_or_else(|| MapTideError::AlignmentStartNotFound)?
+            .get();
+
+        let end = record
+            .alignment_end()
+            .ok_or_else(|| MapTideError::AlignmentEndNotFound)?
+            .get();
+
+        // Mates that would start before this record have been passed, so are never found
+        let later = self.pending.split_off(&(ref_id, start, String::new()));
+        let mut ready: Ready = mem::replace(&mut self.pending, later)
+            .into_values()
+            .map(|x| {
+                self.remove_start(&x);
+                (x, OverlapMask::new())
+            })
+            .collect();
+
+        let name = match record.read_name() {
+            Some(x) => x.to_string(),
+            None => {
+                ready.push((record, OverlapMask::new()));
+                return Ok(ready);
+            }
+        };
+
+        if let Some(first) = self.pending.remove(&(ref_id, start, name.clone())) {
+            self.remove_start(&first);
+            let (first_mask, second_mask) = self.resolve(&first, &record, start)?;
+            ready.push((first, first_mask));
+            ready.push((record, second_mask));
+        } else {
+            match mate_start(&record, ref_id) {
+                Some(mate_start) if mate_start >= start && mate_start <= end => {
+                    *self.starts.entry(start).or_insert(0) += 1;
+                    self.pending.insert((ref_id, mate_start, name), record);
+                }
+                _ => ready.push((record, OverlapMask::new())),
+            }
+        }
+
+        Ok(ready)
+    }
+
+    /// Return the records still waiting for their mate, once there are no more records.
+    pub fn finish(&mut self) -> Ready {
+        self.starts.clear();
+        mem::take(&mut self.pending)
+            .into_values()
+            .map(|x| (x, OverlapMask::new()))
+            .collect()
+    }
+
+    /// Return the earliest alignment start of the records waiting for their mate, if there are any.
+    pub fn earliest_start(&self) -> Option<usize> {
+        self.starts.keys().next().copied()
+    }
+
+    /// Remove the alignment start of `record`, which is no longer pending, from the pending starts.
+    fn remove_start(&mut self, record: &Record) {
+        if let Some(start) = record.alignment_start().map(|x| x.get()) {
+            if let Some(n) = self.starts.get_mut(&start) {
+                *n -= 1;
+                if *n == 0 {
+                    self.starts.remove(&start);
+                }
+            }
+        }
+    }
+
+    /// Return the bases of `record` from `start` onwards, with their quality, keyed by coordinate.
+    fn alleles(
+        &self,
+        record: &Record,
+        start: usize,
+    ) -> Result<BTreeMap<Coordinate, (usize, u8)>, MapTideError> {
+        let seq = record.sequence();
+        let quals = record.quality_scores();
+        let mut alleles = BTreeMap::new();
+
+        walk_record(
+            record,
+            self.base_quality,
+            self.trim_ends,
+            start,
+            usize::MAX,
+            |step| {
+                let allele = match step {
+                    Step::Match { seq_pos, .. } | Step::Insertion { seq_pos, .. } => {
+                        get_base(seq, quals, seq_pos)?
+                    }
+                    // A deletion takes the quality of the base following it
+                    Step::Deletion { seq_pos, .. } => {
+                        (DELETION_INDEX, quals.get(seq_pos).map_or(0, |x| x.get()))
+                    }
+                };
+                alleles.insert(step.coordinate(), allele);
+                Ok(())
+            },
+        )?;
+
+        Ok(alleles)
+    }
+
+    /// Compare the bases of the `first` and `second` mates from `start`, where the second mate begins, and return their masks.
+    fn resolve(
+        &self,
+        first: &Record,
+        second: &Record,
+        start: usize,
+    ) -> Result<(OverlapMask, OverlapMask), MapTideError> {
+        let first_alleles = self.alleles(first, start)?;
+        let second_alleles = self.alleles(second, start)?;
+
+        let mut first_mask = OverlapMask::new();
+        let mut second_mask = OverlapMask::new();
+
+        for (coord, (first_index, first_quality)) in first_alleles.iter() {
+            let (second_index, second_quality) = match second_alleles.get(coord) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            match self.mode {
+                OverlapMode::Quality if second_quality > first_quality => {
+                    first_mask.insert(*coord, Resolution::Skip);
+                }
+                OverlapMode::Consensus if second_index != first_index => {
+                    first_mask.insert(*coord, Resolution::Ambiguous);
+                    second_mask.insert(*coord, Resolution::Skip);
+                }
+                _ => {
+                    second_mask.insert(*coord, Resolution::Skip);
+                }
+            }
+        }
+
+        Ok((first_mask, second_mask))
+    }
+}
+
+/// Return the start of the mate of `record`, if it is a mapped mate on the reference at `ref_id`.
+fn mate_start(record: &Record, ref_id: usize) -> Option<usize> {
+    let flags = record.flags();
+
+    if !flags.is_segmented() || flags.is_mate_unmapped() {
+        return None;
+    }
+
+    if record.mate_reference_sequence_id() != Some(ref_id) {
+        return None;
+    }
+
+    record.mate_alignment_start().map(|x| x.get())
+}
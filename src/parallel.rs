@@ -0,0 +1,238 @@
+use noodles::bam::bai;
+use noodles::core::region::Interval;
+use noodles::core::{Position, Region};
+use noodles::sam::alignment::Record;
+use noodles::sam::header::ReferenceSequences;
+use std::panic;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::counts::Counts;
+use crate::error::MapTideError;
+use crate::options::PileupOptions;
+use crate::overlap::{MateOverlaps, OverlapMask};
+use crate::qc::ReadStats;
+use crate::{
+    count_record, get_reader, init_coordinates, init_maps, BamReader, CoordinateMap, Pileup,
+};
+
+/// A window of a reference, from `start` to `end` inclusive, that is counted by a single worker.
+struct Window {
+    ref_name: String,
+    start: usize,
+    end: usize,
+}
+
+/// Counts of each window counted by a worker, alongside the index of the window, and the statistics of the records starting in the windows.
+type WindowCounts = (Vec<(usize, Counts, CoordinateMap)>, ReadStats);
+
+/// Split each reference into windows of `window_size` positions, or a single window if `window_size` is `None`.
+fn split_windows(ref_seqs: &ReferenceSequences, window_size: Option<usize>) -> Vec<Window> {
+    let mut windows = Vec::new();
+
+    for (ref_name, ref_seq) in ref_seqs.iter() {
+        let ref_length = ref_seq.length().get();
+        let size = window_size.unwrap_or(ref_length).max(1);
+
+        let mut start = 1;
+        while start <= ref_length {
+            let end = (start + size - 1).min(ref_length);
+            windows.push(Window {
+                ref_name: ref_name.to_owned(),
+                start,
+                end,
+            });
+            start = end + 1;
+        }
+    }
+
+    windows
+}
+
+/// Count the bases within `window`, using the index to fetch only the records intersecting it.
+///
+/// Statistics are added to `read_stats` only for records starting within the window, so that records spanning several windows are added once.
+///
+/// Both mates of a pair are fetched for any window their overlap intersects, so overlaps are resolved the same way in every window.
+fn count_window(
+    reader: &mut BamReader,
+    ref_seqs: &ReferenceSequences,
+    index: &bai::Index,
+    window: &Window,
+    options: &PileupOptions,
+    read_stats: &mut ReadStats,
+) -> Result<(Counts, CoordinateMap), MapTideError> {
+    let start = Position::new(window.start).ok_or_else(|| MapTideError::IndexNotFound)?;
+    let end = Position::new(window.end).ok_or_else(|| MapTideError::IndexNotFound)?;
+    let region = Region::new(window.ref_name.clone(), Interval::from(start..=end));
+
+    // Thread-local counts over the window
+    let mut ref_arr = Counts::new(window.end - window.start + 1, options.count_options());
+    let mut ins_map = CoordinateMap::new();
+
+    let mut overlaps = MateOverlaps::new(
+        options.overlaps(),
+        options.base_quality(),
+        options.trim_ends(),
+    );
+
+    let mut count = |record: Record, mask: OverlapMask| {
+        count_record(
+            &mut ref_arr,
+            window.start - 1,
+            &mut ins_map,
+            &record,
+            &mask,
+            options.base_quality(),
+            options.trim_ends(),
+            window.start,
+            window.end,
+        )
+    };
+
+    for result in reader.query(ref_seqs, index, &region)? {
+        let record = result?;
+        let reason = options.filter_reason(&record)?;
+
+        if record
+            .alignment_start()
+            .map_or(false, |x| x.get() >= window.start)
+        {
+            read_stats.add_record(&record, reason);
+        }
+
+        if reason.is_some() {
+            continue;
+        }
+
+        for (record, mask) in overlaps.push(record)? {
+            count(record, mask)?;
+        }
+    }
+
+    for (record, mask) in overlaps.finish() {
+        count(record, mask)?;
+    }
+
+    Ok((ref_arr, ins_map))
+}
+
+/// Count the bases across all references of the BAM file located at `bam_path`, using a worker for each thread in `options`.
+///
+/// Each reference is split into windows of the window size in `options` (or a single window if it is `None`), which are fetched through the index located at `bai_path` and counted independently.
+///
+/// Records spanning several windows are only counted within each window, so the counts are identical to a sequential pileup.
+///
+/// Read statistics are gathered from the records fetched for each window, and from the unplaced records at the end of the file. Placed records that intersect no window, such as unmapped records placed beyond the end of their reference, are not included, so the statistics can differ slightly from a sequential pileup.
+pub fn pileup_windows(
+    bam_path: String,
+    bai_path: String,
+    options: &PileupOptions,
+) -> Result<Pileup, MapTideError> {
+    // Create initial maps
+    let (mut ref_arrs, mut ins_maps, mut ref_lengths) = init_maps();
+
+    // Reference sequence information
+    let ref_seqs = get_reader(bam_path.clone(), 1)?.read_reference_sequences()?;
+
+    // Add reference sequence information to HashMaps
+    for reff in ref_seqs.iter() {
+        ref_lengths.insert(reff.0.to_owned(), reff.1.length().get());
+    }
+
+    // Initialise coordinates
+    init_coordinates(
+        &mut ref_arrs,
+        &mut ins_maps,
+        &ref_lengths,
+        None,
+        options.count_options(),
+    )?;
+
+    // Read the index file, which is shared by the workers
+    let index = bai::read(bai_path)?;
+
+    let windows = split_windows(&ref_seqs, options.window_size());
+
+    // Index of the next window to be counted
+    let next = AtomicUsize::new(0);
+
+    let results: Vec<Result<WindowCounts, MapTideError>> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..options.threads().min(windows.len()).max(1))
+            .map(|_| {
+                scope.spawn(|| -> Result<WindowCounts, MapTideError> {
+                    // Each worker has its own reader
+                    let mut reader = get_reader(bam_path.clone(), 1)?;
+                    let worker_ref_seqs = reader.read_reference_sequences()?;
+                    let mut counted = Vec::new();
+                    let mut read_stats = ReadStats::default();
+
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let window = match windows.get(i) {
+                            Some(x) => x,
+                            None => break,
+                        };
+
+                        let (ref_arr, ins_map) = count_window(
+                            &mut reader,
+                            &worker_ref_seqs,
+                            &index,
+                            window,
+                            options,
+                            &mut read_stats,
+                        )?;
+                        counted.push((i, ref_arr, ins_map));
+                    }
+
+                    Ok((counted, read_stats))
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect()
+    });
+
+    let mut read_stats = ReadStats::default();
+
+    // Merge the counts of each window into the counts of its reference
+    for result in results {
+        let (counted, worker_read_stats) = result?;
+        read_stats.add(&worker_read_stats);
+
+        for (i, window_arr, window_ins_map) in counted {
+            let window = windows.get(i).ok_or_else(|| MapTideError::IndexNotFound)?;
+
+            let (ref_arr, offset) = ref_arrs
+                .get_mut(&window.ref_name)
+                .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+            ref_arr.add_counts(window.start - *offset - 1, &window_arr)?;
+
+            ins_maps
+                .get_mut(&window.ref_name)
+                .ok_or_else(|| MapTideError::KeyNotFound)?
+                .extend(window_ins_map);
+        }
+    }
+
+    // Unplaced records are not fetched for any window, so are added to the statistics separately
+    let mut reader = get_reader(bam_path, 1)?;
+    for result in reader.query_unmapped(&index)? {
+        let record = result?;
+        let reason = options.filter_reason(&record)?;
+        read_stats.add_record(&record, reason);
+    }
+
+    Ok(Pileup {
+        ref_names: ref_seqs.keys().cloned().collect(),
+        ref_arrs,
+        ins_maps,
+        ref_lengths,
+        options: options.clone(),
+        read_stats,
+    })
+}
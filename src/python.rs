@@ -0,0 +1,953 @@
+use arrow::pyarrow::PyArrowConvert;
+use noodles::core::region::ParseError;
+use noodles::core::Region;
+use noodles::sam::record::Flags;
+use pyo3::exceptions::{
+    PyException, PyIOError, PyIndexError, PyKeyError, PyOverflowError, PyValueError,
+};
+use pyo3::prelude::*;
+use pyo3::pyclass::CompareOp;
+use pyo3::types::{PyDict, PyList, PyString};
+use std::collections::BTreeMap;
+
+use crate::error::MapTideError;
+use crate::stream::{PileupStream, Row};
+use crate::{
+    binary, columnar, depth, get_base_index, mpileup, pileup_all, pileup_groups, pileup_query,
+    summary, tsv, Coordinate, CountOptions, GroupBy, OverlapMode, Pileup, PileupOptions, TagFilter,
+    BASES,
+};
+
+impl IntoPy<PyObject> for Coordinate {
+    fn into_py(self, py: Python<'_>) -> PyObject {
+        (self.0, self.1).into_py(py)
+    }
+}
+
+impl From<MapTideError> for PyErr {
+    fn from(e: MapTideError) -> Self {
+        match e {
+            MapTideError::KeyNotFound => PyKeyError::new_err(e.to_string()),
+            MapTideError::IndexNotFound => PyIndexError::new_err(e.to_string()),
+            MapTideError::IntegerOverflow => PyOverflowError::new_err(e.to_string()),
+            MapTideError::NoPileups => PyValueError::new_err(e.to_string()),
+            MapTideError::IOError(e) => PyIOError::new_err(e.to_string()),
+            _ => PyException::new_err(e.to_string()),
+        }
+    }
+}
+
+/// Python iterator over the rows of a pileup, in sorted order.
+#[pyclass]
+pub struct PileupIterator {
+    stream: PileupStream,
+}
+
+impl From<PileupStream> for PileupIterator {
+    fn from(stream: PileupStream) -> Self {
+        Self { stream }
+    }
+}
+
+#[pymethods]
+impl PileupIterator {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<'_, Self>) -> PyResult<Option<Row>> {
+        let py = slf.py();
+        let stream = &mut slf.stream;
+
+        // Records are read and counted without holding the GIL
+        Ok(py.allow_threads(|| stream.next().transpose())?)
+    }
+}
+
+/// Convert `pileup` into a Python dictionary, mapping each reference to its coordinates and counts in sorted order.
+fn to_py_dict(py: Python<'_>, pileup: &Pileup) -> PyResult<PyObject> {
+    let ref_dicts = PyDict::new(py);
+
+    for ref_name in pileup.ref_names() {
+        let rows = PyDict::new(py);
+        for (coord, row) in pileup.sorted_rows(ref_name)? {
+            rows.set_item(coord.into_py(py), &row[..])?;
+        }
+        ref_dicts.set_item(ref_name, rows)?;
+    }
+
+    Ok(ref_dicts.into())
+}
+
+/// Options controlling how records are filtered and counted in a pileup, and how it is written.
+///
+/// The functions of the Python API take either an instance through `options`, or the same options as keyword arguments, which are described in full here.
+///
+/// Parameters
+/// ----------
+/// mapping_quality : int, optional
+///     Minimum mapping quality for a read to be included in the pileup (default: 0)
+/// base_quality : int, optional
+///     Minimum base quality for a base within a read to be included in the pileup (default: 0)
+/// trim_ends : int, optional
+///     Number of bases to ignore at each end of the aligned part of a read, after any soft clips (default: 0)
+/// overlaps : str, optional
+///     How the bases of a pair of mates are counted where the mates overlap. One of `count` (both bases), `quality` (the higher quality base) or `consensus` (once if the bases agree, or as an N if not). mpileup output only supports `count` (default: `count`)
+/// required_flags : int, optional
+///     Flags that a read must have all of to be included in the pileup (default: 0)
+/// excluded_flags : int, optional
+///     Flags that a read must have none of to be included in the pileup (default: unmapped, secondary, QC-failed, duplicate and supplementary reads)
+/// min_read_length : int, optional
+///     Minimum length of the sequence of a read for it to be included in the pileup (default: 0)
+/// read_groups : list of str, optional
+///     Read groups to include in the pileup, from the `RG` tag of each read (default: all reads)
+/// tag_filters : list of str, optional
+///     Filters on the tags of each read, which a read must pass all of to be included in the pileup. Each is one of `TAG` (the read has the tag), `!TAG` (it does not), `TAG OP VALUE` where `OP` is one of `==`, `!=`, `<`, `<=`, `>` or `>=`, or `TAG in VALUES` (or `!in`) where `VALUES` are separated by commas, or read one per line from a file if written as `@PATH` (default: no filters)
+/// threads : int, optional
+///     Number of threads used to read the BAM file (default: 1)
+/// window_size : int, optional
+///     When counting all positions with an index and multiple threads, the size of the windows that each reference is split into for counting in parallel (default: whole references)
+/// width : int, optional
+///     Integer width of the counters stored at each position, one of 16, 32 or 64 (default: 64)
+/// storage : str, optional
+///     Storage of the counters of each reference, one of `dense`, `sparse` or `auto`. Sparse counters are only kept for positions that have been counted, and `auto` switches a reference to dense counters once enough of it has been counted (default: dense)
+/// saturate : bool, optional
+///     Stop counters at the maximum value of their width, instead of raising an `OverflowError` (default: False)
+/// qualities : bool, optional
+///     Collect the base and mapping qualities at each position, when streaming rows (default: False)
+/// end_distance : int, optional
+///     Bases within this distance of the nearer aligned end of their read are counted as near the end, when collecting qualities (default: 10)
+/// stats : bool, optional
+///     Write additional per-position statistics in TSV output, including the mean and standard deviation of the base quality of each base, the mean mapping quality, and the distance of each base from the nearer aligned end of its reads (default: False)
+/// decimals : int, optional
+///     Number of decimal places to write the statistics with (default: 3)
+/// bgzip : bool, optional
+///     Compress TSV output with BGZF (default: False)
+#[pyclass(name = "PileupOptions")]
+#[derive(Clone)]
+pub struct PyPileupOptions {
+    options: PileupOptions,
+}
+
+#[pymethods]
+impl PyPileupOptions {
+    #[new]
+    #[args(
+        mapping_quality = "0",
+        base_quality = "0",
+        trim_ends = "0",
+        overlaps = "\"count\"",
+        required_flags = "0",
+        excluded_flags = "None",
+        min_read_length = "0",
+        read_groups = "None",
+        tag_filters = "None",
+        threads = "1",
+        window_size = "None",
+        width = "64",
+        storage = "\"dense\"",
+        saturate = "false",
+        qualities = "false",
+        end_distance = "10",
+        stats = "false",
+        decimals = "3",
+        bgzip = "false"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        mapping_quality: usize,
+        base_quality: usize,
+        trim_ends: usize,
+        overlaps: &str,
+        required_flags: u16,
+        excluded_flags: Option<u16>,
+        min_read_length: usize,
+        read_groups: Option<Vec<String>>,
+        tag_filters: Option<Vec<String>>,
+        threads: usize,
+        window_size: Option<usize>,
+        width: usize,
+        storage: &str,
+        saturate: bool,
+        qualities: bool,
+        end_distance: usize,
+        stats: bool,
+        decimals: usize,
+        bgzip: bool,
+    ) -> PyResult<Self> {
+        let tag_filters = tag_filters
+            .unwrap_or_default()
+            .iter()
+            .map(|x| x.parse())
+            .collect::<Result<Vec<TagFilter>, MapTideError>>()?;
+
+        let mut builder = PileupOptions::builder()
+            .set_mapping_quality(mapping_quality)
+            .set_base_quality(base_quality)
+            .set_trim_ends(trim_ends)
+            .set_overlaps(OverlapMode::from_name(overlaps)?)
+            .set_required_flags(Flags::from(required_flags))
+            .set_min_read_length(min_read_length)
+            .set_read_groups(read_groups.unwrap_or_default())
+            .set_tag_filters(tag_filters)
+            .set_threads(threads)
+            .set_window_size(window_size)
+            .set_count_options(CountOptions::new(width, storage, saturate)?)
+            .set_qualities(qualities)
+            .set_end_distance(end_distance)
+            .set_stats(stats)
+            .set_decimals(decimals)
+            .set_bgzip(bgzip);
+
+        // Keep the default excluded flags unless others are given
+        if let Some(flags) = excluded_flags {
+            builder = builder.set_excluded_flags(Flags::from(flags));
+        }
+
+        Ok(Self {
+            options: builder.build()?,
+        })
+    }
+
+    #[getter]
+    fn mapping_quality(&self) -> usize {
+        self.options.mapping_quality()
+    }
+
+    #[getter]
+    fn base_quality(&self) -> usize {
+        self.options.base_quality()
+    }
+
+    #[getter]
+    fn trim_ends(&self) -> usize {
+        self.options.trim_ends()
+    }
+
+    #[getter]
+    fn overlaps(&self) -> &'static str {
+        self.options.overlaps().name()
+    }
+
+    #[getter]
+    fn required_flags(&self) -> u16 {
+        self.options.required_flags().bits()
+    }
+
+    #[getter]
+    fn excluded_flags(&self) -> u16 {
+        self.options.excluded_flags().bits()
+    }
+
+    #[getter]
+    fn min_read_length(&self) -> usize {
+        self.options.min_read_length()
+    }
+
+    #[getter]
+    fn read_groups(&self) -> Vec<String> {
+        self.options.read_groups().to_vec()
+    }
+
+    #[getter]
+    fn tag_filters(&self) -> Vec<String> {
+        self.options
+            .tag_filters()
+            .iter()
+            .map(|x| x.to_string())
+            .collect()
+    }
+
+    #[getter]
+    fn threads(&self) -> usize {
+        self.options.threads()
+    }
+
+    #[getter]
+    fn window_size(&self) -> Option<usize> {
+        self.options.window_size()
+    }
+
+    #[getter]
+    fn width(&self) -> usize {
+        self.options.count_options().width.bits()
+    }
+
+    #[getter]
+    fn storage(&self) -> &'static str {
+        self.options.count_options().storage.name()
+    }
+
+    #[getter]
+    fn saturate(&self) -> bool {
+        self.options.count_options().saturate
+    }
+
+    #[getter]
+    fn qualities(&self) -> bool {
+        self.options.qualities()
+    }
+
+    #[getter]
+    fn end_distance(&self) -> usize {
+        self.options.end_distance()
+    }
+
+    #[getter]
+    fn stats(&self) -> bool {
+        self.options.stats()
+    }
+
+    #[getter]
+    fn decimals(&self) -> usize {
+        self.options.decimals()
+    }
+
+    #[getter]
+    fn bgzip(&self) -> bool {
+        self.options.bgzip()
+    }
+
+    /// Write the options as JSON.
+    fn to_json(&self) -> PyResult<String> {
+        Ok(self.options.to_json()?)
+    }
+
+    /// Read options from JSON.
+    #[staticmethod]
+    fn from_json(s: &str) -> PyResult<Self> {
+        Ok(Self {
+            options: PileupOptions::from_json(s)?,
+        })
+    }
+
+    /// Write the options as TOML.
+    fn to_toml(&self) -> PyResult<String> {
+        Ok(self.options.to_toml()?)
+    }
+
+    /// Read options from TOML.
+    #[staticmethod]
+    fn from_toml(s: &str) -> PyResult<Self> {
+        Ok(Self {
+            options: PileupOptions::from_toml(s)?,
+        })
+    }
+
+    fn __richcmp__(&self, other: &Self, op: CompareOp, py: Python<'_>) -> PyObject {
+        match op {
+            CompareOp::Eq => (self.options == other.options).into_py(py),
+            CompareOp::Ne => (self.options != other.options).into_py(py),
+            _ => py.NotImplemented(),
+        }
+    }
+}
+
+/// Convert the counts in `row` into a Python list, or a dictionary keyed by base if `annotated` is `true`.
+fn to_py_frequencies(py: Python<'_>, row: &[usize; 6], annotated: bool) -> PyResult<PyObject> {
+    if annotated {
+        let frequencies = PyDict::new(py);
+        for (base, count) in BASES.iter().zip(row.iter()) {
+            frequencies.set_item(base, count)?;
+        }
+        Ok(frequencies.into())
+    } else {
+        Ok(row[..].into_py(py))
+    }
+}
+
+/// Counts obtained from a pileup, which are kept in Rust and converted into Python objects on request.
+#[pyclass(name = "Pileup")]
+pub struct PyPileup {
+    pileup: Pileup,
+}
+
+impl From<Pileup> for PyPileup {
+    fn from(pileup: Pileup) -> Self {
+        Self { pileup }
+    }
+}
+
+#[pymethods]
+impl PyPileup {
+    /// Names of the counted references, in the order they appear in the BAM header.
+    #[getter]
+    fn references(&self) -> Vec<String> {
+        self.pileup.ref_names().to_vec()
+    }
+
+    /// Options that the pileup was counted with.
+    #[getter]
+    fn options(&self) -> PyPileupOptions {
+        PyPileupOptions {
+            options: self.pileup.options().clone(),
+        }
+    }
+
+    /// Statistics of the records visited while counting the pileup.
+    ///
+    /// Records filtered out of the pileup are counted by reason, and the distributions are over the records that were counted.
+    #[getter]
+    fn read_stats(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let stats = self.pileup.read_stats();
+        let dict = PyDict::new(py);
+
+        dict.set_item("records", stats.records)?;
+        dict.set_item("counted", stats.counted)?;
+        dict.set_item("filtered", stats.filtered())?;
+        dict.set_item("filtered_flags", &stats.filtered_flags)?;
+        dict.set_item("filtered_required_flags", stats.filtered_required_flags)?;
+        dict.set_item("filtered_read_length", stats.filtered_read_length)?;
+        dict.set_item("filtered_mapping_quality", stats.filtered_mapping_quality)?;
+        dict.set_item("filtered_read_group", stats.filtered_read_group)?;
+        dict.set_item("filtered_tag", stats.filtered_tag)?;
+        dict.set_item("mapping_qualities", &stats.mapping_qualities)?;
+        dict.set_item("aligned_lengths", &stats.aligned_lengths)?;
+        dict.set_item("read_bases", stats.read_bases)?;
+        dict.set_item("soft_clipped_bases", stats.soft_clipped_bases)?;
+        dict.set_item("soft_clipped_records", stats.soft_clipped_records)?;
+        dict.set_item("soft_clip_fraction", stats.soft_clip_fraction())?;
+        dict.set_item(
+            "soft_clipped_record_fraction",
+            stats.soft_clipped_record_fraction(),
+        )?;
+        dict.set_item("insert_sizes", &stats.insert_sizes)?;
+
+        Ok(dict.into())
+    }
+
+    /// Length of `chrom` in the BAM header.
+    fn length(&self, chrom: &str) -> PyResult<usize> {
+        Ok(self.pileup.ref_length(chrom)?)
+    }
+
+    /// Return the first and last reference positions of `chrom` that were counted.
+    fn bounds(&self, chrom: &str) -> PyResult<(usize, usize)> {
+        Ok(self.pileup.bounds(chrom)?)
+    }
+
+    /// Return the total count at position `pos` and insert position `ins_pos` of `chrom`.
+    #[args(ins_pos = "0")]
+    fn depth(&self, chrom: &str, pos: usize, ins_pos: usize) -> PyResult<usize> {
+        Ok(self.pileup.depth(chrom, Coordinate(pos, ins_pos))?)
+    }
+
+    /// Return the base frequencies at position `pos` and insert position `ins_pos` of `chrom`.
+    #[args(ins_pos = "0", annotated = "false")]
+    fn frequencies(
+        &self,
+        py: Python<'_>,
+        chrom: &str,
+        pos: usize,
+        ins_pos: usize,
+        annotated: bool,
+    ) -> PyResult<PyObject> {
+        let row = self.pileup.frequencies(chrom, Coordinate(pos, ins_pos))?;
+        to_py_frequencies(py, &row, annotated)
+    }
+
+    /// Return the base frequencies of the insertions following position `pos` of `chrom`, keyed by coordinate.
+    #[args(annotated = "false")]
+    fn insertions_at(
+        &self,
+        py: Python<'_>,
+        chrom: &str,
+        pos: usize,
+        annotated: bool,
+    ) -> PyResult<PyObject> {
+        let rows = PyDict::new(py);
+        for (coord, row) in self.pileup.insertions_at(chrom, pos)? {
+            rows.set_item(coord.into_py(py), to_py_frequencies(py, &row, annotated)?)?;
+        }
+        Ok(rows.into())
+    }
+
+    /// Return a new pileup containing only the counts within `region`, of the form `CHROM:START-END`.
+    fn slice(&self, py: Python<'_>, region: String) -> PyResult<Self> {
+        let region = to_region(region)?;
+        let pileup = py.allow_threads(|| self.pileup.slice(&region))?;
+        Ok(Self::from(pileup))
+    }
+
+    /// Add the counts of `other` to the pileup, position by position, including insertions.
+    ///
+    /// Both pileups must have the same references, with the same lengths and counted positions.
+    fn add(&mut self, py: Python<'_>, other: PyRef<'_, Self>) -> PyResult<()> {
+        let other = &other.pileup;
+        py.allow_threads(|| self.pileup.add(other))?;
+        Ok(())
+    }
+
+    fn __add__(&self, py: Python<'_>, other: PyRef<'_, Self>) -> PyResult<Self> {
+        let other = &other.pileup;
+        let pileup = py.allow_threads(|| -> Result<Pileup, MapTideError> {
+            let mut merged = self.pileup.clone();
+            merged.add(other)?;
+            Ok(merged)
+        })?;
+        Ok(Self::from(pileup))
+    }
+
+    fn __iadd__(&mut self, py: Python<'_>, other: PyRef<'_, Self>) -> PyResult<()> {
+        self.add(py, other)
+    }
+
+    /// Return the coverage of each reference, over the positions that were counted.
+    ///
+    /// Each summary is a dictionary of `chrom`, `start`, `end`, `mean_depth`, `median_depth`, `breadth_1x`, `breadth_10x`, `breadth_20x`, `n_fraction` and `deletion_fraction`.
+    fn summary(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let summaries = py.allow_threads(|| summary::summarise(&self.pileup))?;
+        let list = PyList::empty(py);
+
+        for s in summaries {
+            let dict = PyDict::new(py);
+            dict.set_item("chrom", s.ref_name)?;
+            dict.set_item("start", s.start)?;
+            dict.set_item("end", s.end)?;
+            dict.set_item("mean_depth", s.mean_depth)?;
+            dict.set_item("median_depth", s.median_depth)?;
+            for (d, breadth) in summary::BREADTH_DEPTHS.iter().zip(s.breadth.iter()) {
+                dict.set_item(format!("breadth_{}x", d), breadth)?;
+            }
+            dict.set_item("n_fraction", s.n_fraction)?;
+            dict.set_item("deletion_fraction", s.deletion_fraction)?;
+            list.append(dict)?;
+        }
+
+        Ok(list.into())
+    }
+
+    /// Write a table of the coverage of each reference to `path`, or to stdout if `path` is `None`.
+    #[args(path = "None", decimals = "3")]
+    fn write_summary(&self, py: Python<'_>, path: Option<String>, decimals: usize) -> PyResult<()> {
+        py.allow_threads(|| summary::write_summary(&self.pileup, path, decimals))?;
+        Ok(())
+    }
+
+    /// Return a histogram of the depth of each reference, mapping each reference to the number of positions at each depth from zero up to `cap`.
+    ///
+    /// The final bucket counts all positions with a depth of at least `cap`.
+    #[args(cap = "1000")]
+    fn depth_histogram(&self, py: Python<'_>, cap: usize) -> PyResult<PyObject> {
+        let histograms = py.allow_threads(|| summary::depth_histograms(&self.pileup, cap))?;
+        let dict = PyDict::new(py);
+
+        for (ref_name, histogram) in histograms {
+            dict.set_item(ref_name, histogram)?;
+        }
+
+        Ok(dict.into())
+    }
+
+    /// Write the depth histogram of each reference to `path`, or to stdout if `path` is `None`.
+    #[args(path = "None", cap = "1000")]
+    fn write_histogram(&self, py: Python<'_>, path: Option<String>, cap: usize) -> PyResult<()> {
+        py.allow_threads(|| summary::write_histogram(&self.pileup, path, cap))?;
+        Ok(())
+    }
+
+    /// Save the pileup to a binary file located at `path`, which can be loaded without the BAM file.
+    fn save(&self, py: Python<'_>, path: String) -> PyResult<()> {
+        py.allow_threads(|| binary::write_pileup(&self.pileup, path))?;
+        Ok(())
+    }
+
+    /// Load a pileup saved with `save` from the file located at `path`.
+    #[staticmethod]
+    fn load(py: Python<'_>, path: String) -> PyResult<Self> {
+        let pileup = py.allow_threads(|| binary::read_pileup(path))?;
+        Ok(Self::from(pileup))
+    }
+
+    /// Convert the pileup into a dictionary, mapping each reference to its coordinates and counts in sorted order.
+    fn to_dict(&self, py: Python<'_>) -> PyResult<PyObject> {
+        to_py_dict(py, &self.pileup)
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PileupRowIterator {
+        PileupRowIterator {
+            pileup: slf.into(),
+            ref_index: 0,
+            ref_name: None,
+            coord: None,
+        }
+    }
+}
+
+/// Python iterator over the rows of a `Pileup`, in sorted order.
+///
+/// Rows are converted one at a time, and the rows of each reference share a single Python string for its name.
+#[pyclass]
+pub struct PileupRowIterator {
+    pileup: Py<PyPileup>,
+    ref_index: usize,
+    ref_name: Option<Py<PyString>>,
+    coord: Option<Coordinate>,
+}
+
+#[pymethods]
+impl PileupRowIterator {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<'_, Self>) -> PyResult<Option<PyObject>> {
+        let py = slf.py();
+        let pileup = slf.pileup.clone_ref(py);
+        let pileup = pileup.borrow(py);
+
+        loop {
+            let ref_name = match pileup.pileup.ref_names().get(slf.ref_index) {
+                Some(x) => x,
+                None => return Ok(None),
+            };
+
+            if let Some((coord, row)) = pileup.pileup.next_row(ref_name, slf.coord)? {
+                slf.coord = Some(coord);
+                let name = slf
+                    .ref_name
+                    .get_or_insert_with(|| PyString::new(py, ref_name).into())
+                    .clone_ref(py);
+
+                return Ok(Some((name, coord.0, coord.1, row).into_py(py)));
+            }
+
+            // Move on to the next reference
+            slf.ref_index += 1;
+            slf.ref_name = None;
+            slf.coord = None;
+        }
+    }
+}
+
+/// Parse `region`, which is of the form `CHROM:START-END`.
+fn to_region(region: String) -> PyResult<Region> {
+    region
+        .parse()
+        .map_err(|x: ParseError| PyException::new_err(x.to_string()))
+}
+
+#[pyfunction]
+fn all(
+    py: Python<'_>,
+    bam_path: String,
+    bai_path: Option<String>,
+    options: PyPileupOptions,
+) -> PyResult<PyPileup> {
+    let pileup = py.allow_threads(|| pileup_all(bam_path, bai_path, &options.options))?;
+    Ok(PyPileup::from(pileup))
+}
+
+#[pyfunction]
+fn query(
+    py: Python<'_>,
+    bam_path: String,
+    bai_path: Option<String>,
+    region: String,
+    options: PyPileupOptions,
+) -> PyResult<PyPileup> {
+    let region = to_region(region)?;
+    let pileup =
+        py.allow_threads(|| pileup_query(bam_path, bai_path, &region, &options.options))?;
+    Ok(PyPileup::from(pileup))
+}
+
+#[pyfunction]
+fn groups(
+    py: Python<'_>,
+    bam_path: String,
+    bai_path: Option<String>,
+    region: Option<String>,
+    group_by: &str,
+    options: PyPileupOptions,
+) -> PyResult<BTreeMap<String, PyPileup>> {
+    let region = region.map(to_region).transpose()?;
+    let group_by = GroupBy::from_name(group_by)?;
+    let pileups = py.allow_threads(|| {
+        pileup_groups(
+            bam_path,
+            bai_path,
+            region.as_ref(),
+            group_by,
+            &options.options,
+        )
+    })?;
+
+    Ok(pileups
+        .into_iter()
+        .map(|(group, pileup)| (group, PyPileup::from(pileup)))
+        .collect())
+}
+
+#[pyfunction]
+fn all_iter(
+    py: Python<'_>,
+    bam_path: String,
+    options: PyPileupOptions,
+) -> PyResult<PileupIterator> {
+    let stream = py.allow_threads(|| PileupStream::all(bam_path, &options.options))?;
+    Ok(PileupIterator::from(stream))
+}
+
+#[pyfunction]
+fn query_iter(
+    py: Python<'_>,
+    bam_path: String,
+    bai_path: Option<String>,
+    region: String,
+    options: PyPileupOptions,
+) -> PyResult<PileupIterator> {
+    let region = to_region(region)?;
+    let stream =
+        py.allow_threads(|| PileupStream::query(bam_path, bai_path, region, &options.options))?;
+    Ok(PileupIterator::from(stream))
+}
+
+#[pyfunction]
+fn all_arrow(
+    py: Python<'_>,
+    bam_path: String,
+    bai_path: Option<String>,
+    options: PyPileupOptions,
+) -> PyResult<PyObject> {
+    let pileup = py.allow_threads(|| pileup_all(bam_path, bai_path, &options.options))?;
+    let batch = py.allow_threads(|| columnar::to_record_batch(&pileup))?;
+    batch.to_pyarrow(py)
+}
+
+#[pyfunction]
+fn query_arrow(
+    py: Python<'_>,
+    bam_path: String,
+    bai_path: Option<String>,
+    region: String,
+    options: PyPileupOptions,
+) -> PyResult<PyObject> {
+    let region = to_region(region)?;
+    let pileup =
+        py.allow_threads(|| pileup_query(bam_path, bai_path, &region, &options.options))?;
+    let batch = py.allow_threads(|| columnar::to_record_batch(&pileup))?;
+    batch.to_pyarrow(py)
+}
+
+#[pyfunction]
+fn all_parquet(
+    py: Python<'_>,
+    bam_path: String,
+    bai_path: Option<String>,
+    parquet_path: String,
+    options: PyPileupOptions,
+) -> PyResult<()> {
+    let pileup = py.allow_threads(|| pileup_all(bam_path, bai_path, &options.options))?;
+    py.allow_threads(|| columnar::write_parquet(&pileup, parquet_path))?;
+    Ok(())
+}
+
+#[pyfunction]
+fn query_parquet(
+    py: Python<'_>,
+    bam_path: String,
+    bai_path: Option<String>,
+    region: String,
+    parquet_path: String,
+    options: PyPileupOptions,
+) -> PyResult<()> {
+    let region = to_region(region)?;
+    let pileup =
+        py.allow_threads(|| pileup_query(bam_path, bai_path, &region, &options.options))?;
+    py.allow_threads(|| columnar::write_parquet(&pileup, parquet_path))?;
+    Ok(())
+}
+
+#[pyfunction]
+fn all_tsv(
+    py: Python<'_>,
+    bam_path: String,
+    output_path: Option<String>,
+    options: PyPileupOptions,
+) -> PyResult<()> {
+    let stream = py.allow_threads(|| PileupStream::all(bam_path, &options.options))?;
+    py.allow_threads(|| tsv::write_tsv(stream.with_qualities(), output_path, &options.options))?;
+    Ok(())
+}
+
+#[pyfunction]
+fn query_tsv(
+    py: Python<'_>,
+    bam_path: String,
+    bai_path: Option<String>,
+    region: String,
+    output_path: Option<String>,
+    options: PyPileupOptions,
+) -> PyResult<()> {
+    let region = to_region(region)?;
+    let stream =
+        py.allow_threads(|| PileupStream::query(bam_path, bai_path, region, &options.options))?;
+    py.allow_threads(|| tsv::write_tsv(stream.with_qualities(), output_path, &options.options))?;
+    Ok(())
+}
+
+#[pyfunction]
+fn all_bedgraph(
+    py: Python<'_>,
+    bam_path: String,
+    bai_path: Option<String>,
+    output_path: Option<String>,
+    base: Option<String>,
+    options: PyPileupOptions,
+) -> PyResult<()> {
+    let base_index = get_base_index(base)?;
+    let pileup = py.allow_threads(|| pileup_all(bam_path, bai_path, &options.options))?;
+    py.allow_threads(|| depth::write_bedgraph(&pileup, output_path, base_index))?;
+    Ok(())
+}
+
+#[pyfunction]
+fn query_bedgraph(
+    py: Python<'_>,
+    bam_path: String,
+    bai_path: Option<String>,
+    region: String,
+    output_path: Option<String>,
+    base: Option<String>,
+    options: PyPileupOptions,
+) -> PyResult<()> {
+    let base_index = get_base_index(base)?;
+    let region = to_region(region)?;
+    let pileup =
+        py.allow_threads(|| pileup_query(bam_path, bai_path, &region, &options.options))?;
+    py.allow_threads(|| depth::write_bedgraph(&pileup, output_path, base_index))?;
+    Ok(())
+}
+
+#[pyfunction]
+fn all_depth(
+    py: Python<'_>,
+    bam_path: String,
+    bai_path: Option<String>,
+    output_path: Option<String>,
+    base: Option<String>,
+    options: PyPileupOptions,
+) -> PyResult<()> {
+    let base_index = get_base_index(base)?;
+    let pileup = py.allow_threads(|| pileup_all(bam_path, bai_path, &options.options))?;
+    py.allow_threads(|| depth::write_depth(&pileup, output_path, base_index))?;
+    Ok(())
+}
+
+#[pyfunction]
+fn query_depth(
+    py: Python<'_>,
+    bam_path: String,
+    bai_path: Option<String>,
+    region: String,
+    output_path: Option<String>,
+    base: Option<String>,
+    options: PyPileupOptions,
+) -> PyResult<()> {
+    let base_index = get_base_index(base)?;
+    let region = to_region(region)?;
+    let pileup =
+        py.allow_threads(|| pileup_query(bam_path, bai_path, &region, &options.options))?;
+    py.allow_threads(|| depth::write_depth(&pileup, output_path, base_index))?;
+    Ok(())
+}
+
+#[pyfunction]
+fn all_mpileup(
+    py: Python<'_>,
+    bam_path: String,
+    output_path: Option<String>,
+    reference_path: Option<String>,
+    options: PyPileupOptions,
+) -> PyResult<()> {
+    py.allow_threads(|| {
+        mpileup::write_mpileup(
+            bam_path,
+            None,
+            None,
+            output_path,
+            reference_path,
+            &options.options,
+        )
+    })?;
+    Ok(())
+}
+
+#[pyfunction]
+fn query_mpileup(
+    py: Python<'_>,
+    bam_path: String,
+    bai_path: Option<String>,
+    region: String,
+    output_path: Option<String>,
+    reference_path: Option<String>,
+    options: PyPileupOptions,
+) -> PyResult<()> {
+    let region = to_region(region)?;
+    py.allow_threads(|| {
+        mpileup::write_mpileup(
+            bam_path,
+            bai_path,
+            Some(&region),
+            output_path,
+            reference_path,
+            &options.options,
+        )
+    })?;
+    Ok(())
+}
+
+#[pyfunction]
+fn merge(py: Python<'_>, pileups: Vec<PyRef<'_, PyPileup>>) -> PyResult<PyPileup> {
+    let pileups: Vec<&Pileup> = pileups.iter().map(|x| &x.pileup).collect();
+    let pileup = py.allow_threads(|| Pileup::merge(pileups))?;
+
+    Ok(PyPileup::from(pileup))
+}
+
+#[pyfunction]
+fn parse_region(region: String) -> PyResult<(String, Option<usize>, Option<usize>)> {
+    let region = to_region(region)?;
+    let interval = region.interval();
+    let start = match interval.start() {
+        Some(x) => Some(x.get()),
+        None => None,
+    };
+    let end = match interval.end() {
+        Some(x) => Some(x.get()),
+        None => None,
+    };
+
+    Ok((region.name().to_string(), start, end))
+}
+
+/// A Python module implemented in Rust.
+#[pymodule]
+fn maptide(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(all, m)?)?;
+    m.add_function(wrap_pyfunction!(query, m)?)?;
+    m.add_function(wrap_pyfunction!(groups, m)?)?;
+    m.add_function(wrap_pyfunction!(all_iter, m)?)?;
+    m.add_function(wrap_pyfunction!(query_iter, m)?)?;
+    m.add_function(wrap_pyfunction!(all_arrow, m)?)?;
+    m.add_function(wrap_pyfunction!(query_arrow, m)?)?;
+    m.add_function(wrap_pyfunction!(all_parquet, m)?)?;
+    m.add_function(wrap_pyfunction!(query_parquet, m)?)?;
+    m.add_function(wrap_pyfunction!(all_tsv, m)?)?;
+    m.add_function(wrap_pyfunction!(query_tsv, m)?)?;
+    m.add_function(wrap_pyfunction!(all_bedgraph, m)?)?;
+    m.add_function(wrap_pyfunction!(query_bedgraph, m)?)?;
+    m.add_function(wrap_pyfunction!(all_depth, m)?)?;
+    m.add_function(wrap_pyfunction!(query_depth, m)?)?;
+    m.add_function(wrap_pyfunction!(all_mpileup, m)?)?;
+    m.add_function(wrap_pyfunction!(query_mpileup, m)?)?;
+    m.add_function(wrap_pyfunction!(merge, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_region, m)?)?;
+    m.add_class::<PileupIterator>()?;
+    m.add_class::<PyPileupOptions>()?;
+    m.add_class::<PyPileup>()?;
+    m.add_class::<PileupRowIterator>()?;
+
+    Ok(())
+}
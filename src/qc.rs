@@ -0,0 +1,195 @@
+use noodles::sam::alignment::Record;
+use noodles::sam::record::cigar::op::Kind;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::options::FilterReason;
+
+/// Names of the SAM flags, in order of their bits.
+const FLAG_NAMES: [&str; 12] = [
+    "paired",
+    "proper_pair",
+    "unmapped",
+    "mate_unmapped",
+    "reverse",
+    "mate_reverse",
+    "read1",
+    "read2",
+    "secondary",
+    "qc_fail",
+    "duplicate",
+    "supplementary",
+];
+
+/// Mapping quality recorded for records without one.
+const MISSING_MAPPING_QUALITY: u8 = 255;
+
+/// Return the name of the SAM flag with the single bit `bit`.
+pub fn flag_name(bit: u16) -> &'static str {
+    FLAG_NAMES
+        .get(bit.trailing_zeros() as usize)
+        .copied()
+        .unwrap_or("unknown")
+}
+
+/// Add `n` to the count of `key` in `map`.
+fn add_to<K: Ord>(map: &mut BTreeMap<K, usize>, key: K, n: usize) {
+    *map.entry(key).or_insert(0) += n;
+}
+
+/// Read-level statistics, collected from the records visited while counting a pileup.
+///
+/// Records filtered out of the pileup are counted by the reason they were filtered. The distributions are over the records that were counted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReadStats {
+    /// Number of records visited.
+    pub records: usize,
+
+    /// Number of records counted in the pileup.
+    pub counted: usize,
+
+    /// Number of records filtered by each excluded flag, keyed by flag name.
+    pub filtered_flags: BTreeMap<String, usize>,
+
+    /// Number of records filtered for missing a required flag.
+    pub filtered_required_flags: usize,
+
+    /// Number of records filtered for being shorter than the minimum read length.
+    pub filtered_read_length: usize,
+
+    /// Number of records filtered for being below the minimum mapping quality.
+    pub filtered_mapping_quality: usize,
+
+    /// Number of records filtered for not belonging to an included read group.
+    pub filtered_read_group: usize,
+
+    /// Number of records filtered for failing a tag filter.
+    pub filtered_tag: usize,
+
+    /// Number of counted records with each mapping quality.
+    pub mapping_qualities: BTreeMap<u8, usize>,
+
+    /// Number of counted records with each number of aligned (match or mismatch) bases.
+    pub aligned_lengths: BTreeMap<usize, usize>,
+
+    /// Number of bases of the counted records.
+    pub read_bases: usize,
+
+    /// Number of soft-clipped bases of the counted records.
+    pub soft_clipped_bases: usize,
+
+    /// Number of counted records with at least one soft-clipped base.
+    pub soft_clipped_records: usize,
+
+    /// Number of counted pairs with each insert size, taken from the record with a positive template length.
+    pub insert_sizes: BTreeMap<usize, usize>,
+}
+
+impl ReadStats {
+    /// Add `record`, which was filtered for `reason`, or counted if `reason` is `None`.
+    pub fn add_record(&mut self, record: &Record, reason: Option<FilterReason>) {
+        self.records += 1;
+
+        match reason {
+            Some(FilterReason::ExcludedFlag(bit)) => {
+                add_to(&mut self.filtered_flags, flag_name(bit).to_string(), 1)
+            }
+            Some(FilterReason::RequiredFlags) => self.filtered_required_flags += 1,
+            Some(FilterReason::ReadLength) => self.filtered_read_length += 1,
+            Some(FilterReason::MappingQuality) => self.filtered_mapping_quality += 1,
+            Some(FilterReason::ReadGroup) => self.filtered_read_group += 1,
+            Some(FilterReason::Tag) => self.filtered_tag += 1,
+            None => {
+                self.counted += 1;
+
+                let mapping_quality = record
+                    .mapping_quality()
+                    .map_or(MISSING_MAPPING_QUALITY, |x| x.get());
+                add_to(&mut self.mapping_qualities, mapping_quality, 1);
+
+                let mut aligned = 0;
+                let mut soft_clipped = 0;
+
+                for op in record.cigar().iter() {
+                    match op.kind() {
+                        Kind::Match | Kind::SequenceMatch | Kind::SequenceMismatch => {
+                            aligned += op.len()
+                        }
+                        Kind::SoftClip => soft_clipped += op.len(),
+                        _ => {}
+                    }
+                }
+
+                add_to(&mut self.aligned_lengths, aligned, 1);
+
+                self.read_bases += record.sequence().len();
+                self.soft_clipped_bases += soft_clipped;
+                if soft_clipped > 0 {
+                    self.soft_clipped_records += 1;
+                }
+
+                // Each pair is counted once, from the record with a positive template length
+                let template_length = record.template_length();
+                if record.flags().is_segmented() && template_length > 0 {
+                    add_to(&mut self.insert_sizes, template_length as usize, 1);
+                }
+            }
+        }
+    }
+
+    /// Number of records filtered out of the pileup, for any reason.
+    pub fn filtered(&self) -> usize {
+        self.records - self.counted
+    }
+
+    /// Fraction of the bases of the counted records that are soft-clipped.
+    pub fn soft_clip_fraction(&self) -> f64 {
+        if self.read_bases > 0 {
+            self.soft_clipped_bases as f64 / self.read_bases as f64
+        } else {
+            0.0
+        }
+    }
+
+    /// Fraction of the counted records with at least one soft-clipped base.
+    pub fn soft_clipped_record_fraction(&self) -> f64 {
+        if self.counted > 0 {
+            self.soft_clipped_records as f64 / self.counted as f64
+        } else {
+            0.0
+        }
+    }
+
+    /// Add the statistics of `other`, such as those of another part of the same scan.
+    pub fn add(&mut self, other: &ReadStats) {
+        self.records += other.records;
+        self.counted += other.counted;
+
+        for (name, n) in other.filtered_flags.iter() {
+            add_to(&mut self.filtered_flags, name.clone(), *n);
+        }
+
+        self.filtered_required_flags += other.filtered_required_flags;
+        self.filtered_read_length += other.filtered_read_length;
+        self.filtered_mapping_quality += other.filtered_mapping_quality;
+        self.filtered_read_group += other.filtered_read_group;
+        self.filtered_tag += other.filtered_tag;
+
+        for (mapping_quality, n) in other.mapping_qualities.iter() {
+            add_to(&mut self.mapping_qualities, *mapping_quality, *n);
+        }
+
+        for (length, n) in other.aligned_lengths.iter() {
+            add_to(&mut self.aligned_lengths, *length, *n);
+        }
+
+        self.read_bases += other.read_bases;
+        self.soft_clipped_bases += other.soft_clipped_bases;
+        self.soft_clipped_records += other.soft_clipped_records;
+
+        for (size, n) in other.insert_sizes.iter() {
+            add_to(&mut self.insert_sizes, *size, *n);
+        }
+    }
+}
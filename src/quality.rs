@@ -0,0 +1,202 @@
+use noodles::core::Position;
+use noodles::sam::alignment::Record;
+use noodles::sam::record::sequence::{Base, Sequence};
+use noodles::sam::record::QualityScores;
+use std::collections::BTreeMap;
+
+use crate::error::MapTideError;
+use crate::overlap::{OverlapMask, Resolution};
+use crate::{aligned_bounds, walk_record, Coordinate, Step, DELETION_INDEX, N_INDEX};
+
+/// Quality accumulators of a single position, alongside its base counts.
+///
+/// The distance of a base from the nearer end of the aligned part of its read is also accumulated, as artefactual variants cluster near read ends.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Qualities {
+    /// Sum of the base qualities of each base. Deletions have no quality, so their sum is always zero.
+    pub base_quality_sums: [u64; 6],
+
+    /// Number of each base with a base quality, which excludes deletions, including those counted as an N.
+    pub base_quality_counts: [u64; 6],
+
+    /// Sum of the squared base qualities of each base.
+    pub base_quality_squares: [u64; 6],
+
+    /// Sum of the mapping qualities of the records covering the position.
+    pub mapping_quality_sum: u64,
+
+    /// Number of records covering the position.
+    pub reads: u64,
+
+    /// Sum of the distances of each base from the nearer aligned end of its read.
+    pub end_distance_sums: [u64; 6],
+
+    /// Number of each base within the end distance of the options from the nearer aligned end of its read.
+    pub near_end_counts: [u64; 6],
+}
+
+impl Qualities {
+    /// Mean base quality of the bases at `base_index`, or `None` if there are none with a quality.
+    pub fn mean_base_quality(&self, base_index: usize) -> Option<f64> {
+        let count = self.base_quality_counts[base_index];
+        (count > 0).then(|| self.base_quality_sums[base_index] as f64 / count as f64)
+    }
+
+    /// Standard deviation of the base quality of the bases at `base_index`, or `None` if there are none with a quality.
+    pub fn sd_base_quality(&self, base_index: usize) -> Option<f64> {
+        let count = self.base_quality_counts[base_index];
+        let mean = self.mean_base_quality(base_index)?;
+        let variance = self.base_quality_squares[base_index] as f64 / count as f64 - mean * mean;
+        Some(variance.max(0.0).sqrt())
+    }
+
+    /// Mean distance of the `count` bases at `base_index` from the nearer aligned end of their reads, or `None` if there are none.
+    pub fn mean_end_distance(&self, base_index: usize, count: usize) -> Option<f64> {
+        (count > 0).then(|| self.end_distance_sums[base_index] as f64 / count as f64)
+    }
+
+    /// Mean mapping quality of the records covering the position, or `None` if there are none.
+    pub fn mean_mapping_quality(&self) -> Option<f64> {
+        (self.reads > 0).then(|| self.mapping_quality_sum as f64 / self.reads as f64)
+    }
+
+    /// Add a base at `base_index` with quality `quality`, from a record with mapping quality `mapping_quality`.
+    fn add_base(&mut self, base_index: usize, quality: u8, mapping_quality: u8) {
+        let quality = u64::from(quality);
+        self.base_quality_sums[base_index] += quality;
+        self.base_quality_squares[base_index] += quality * quality;
+        self.base_quality_counts[base_index] += 1;
+        self.add_read(mapping_quality);
+    }
+
+    /// Add a record with mapping quality `mapping_quality` covering the position.
+    fn add_read(&mut self, mapping_quality: u8) {
+        self.mapping_quality_sum += u64::from(mapping_quality);
+        self.reads += 1;
+    }
+
+    /// Add the `distance` of a base at `base_index` from the nearer aligned end of its read, which is near the end if less than `end_distance`.
+    fn add_end_distance(&mut self, base_index: usize, distance: usize, end_distance: usize) {
+        self.end_distance_sums[base_index] += distance as u64;
+        if distance < end_distance {
+            self.near_end_counts[base_index] += 1;
+        }
+    }
+}
+
+/// Quality accumulators over consecutive reference positions.
+pub type QualityArr = Vec<Qualities>;
+
+/// Quality accumulators of inserted bases, sorted by coordinate.
+pub type QualityMap = BTreeMap<Coordinate, Qualities>;
+
+/// Return the index of the base from `seq` at `seq_pos`, and its quality score.
+pub(crate) fn get_base(
+    seq: &Sequence,
+    quals: &QualityScores,
+    seq_pos: Position,
+) -> Result<(usize, u8), MapTideError> {
+    let base_index = match seq.get(seq_pos) {
+        Some(&Base::A) => 0,
+        Some(&Base::C) => 1,
+        Some(&Base::G) => 2,
+        Some(&Base::T) => 3,
+        Some(&Base::N) => 5,
+        Some(_) => return Err(MapTideError::InvalidBase),
+        None => return Err(MapTideError::KeyNotFound),
+    };
+
+    let quality = quals
+        .get(seq_pos)
+        .ok_or_else(|| MapTideError::QualityScoreNotFound)?
+        .get();
+
+    Ok((base_index, quality))
+}
+
+/// Return the distance of the base at `seq_pos` from the nearer of the aligned `bounds` of its read.
+fn end_distance(seq_pos: Position, bounds: (usize, usize)) -> usize {
+    let seq_pos = seq_pos.get();
+    seq_pos
+        .saturating_sub(bounds.0)
+        .min(bounds.1.saturating_sub(seq_pos))
+}
+
+/// Use the CIGAR information of `record` to add the quality of each base in its sequence, and its mapping quality, to `qual_arr` or `qual_map`.
+///
+/// The same bases are visited as by `count_record`, and `mask` is applied in the same way, so the accumulators of each position match its counts. Bases less than `end_distance_limit` from the nearer aligned end of the read are counted as near the end.
+#[allow(clippy::too_many_arguments)]
+pub fn count_qualities(
+    qual_arr: &mut QualityArr,
+    offset: usize,
+    qual_map: &mut QualityMap,
+    record: &Record,
+    mask: &OverlapMask,
+    base_quality: usize,
+    trim_ends: usize,
+    end_distance_limit: usize,
+    region_start: usize,
+    region_end: usize,
+) -> Result<(), MapTideError> {
+    let seq = record.sequence();
+    let quals = record.quality_scores();
+
+    let mapping_quality = record
+        .mapping_quality()
+        .ok_or_else(|| MapTideError::MappingQualityNotFound)?
+        .get();
+
+    let bounds = aligned_bounds(record);
+
+    walk_record(
+        record,
+        base_quality,
+        trim_ends,
+        region_start,
+        region_end,
+        |step| {
+            let resolution = mask.get(&step.coordinate());
+            if resolution == Some(&Resolution::Skip) {
+                return Ok(());
+            }
+
+            // The distance of a deletion is that of the base following it
+            let (base_index, quality, seq_pos) = match step {
+                Step::Match { seq_pos, .. } | Step::Insertion { seq_pos, .. } => {
+                    let (base_index, quality) = get_base(seq, quals, seq_pos)?;
+                    (base_index, quality, seq_pos)
+                }
+                Step::Deletion { seq_pos, .. } => (DELETION_INDEX, 0, seq_pos),
+            };
+
+            // Bases that disagree with their mate are counted as an N
+            let base_index = match resolution {
+                Some(Resolution::Ambiguous) => N_INDEX,
+                _ => base_index,
+            };
+
+            let accumulators = match step {
+                Step::Insertion {
+                    ref_pos, ins_pos, ..
+                } => qual_map.entry(Coordinate(ref_pos, ins_pos)).or_default(),
+                Step::Match { ref_pos, .. } | Step::Deletion { ref_pos, .. } => qual_arr
+                    .get_mut(ref_pos - offset - 1)
+                    .ok_or_else(|| MapTideError::IndexNotFound)?,
+            };
+
+            // Deletions have no base quality, even when counted as an N
+            if let Step::Deletion { .. } = step {
+                accumulators.add_read(mapping_quality);
+            } else {
+                accumulators.add_base(base_index, quality, mapping_quality);
+            }
+
+            accumulators.add_end_distance(
+                base_index,
+                end_distance(seq_pos, bounds),
+                end_distance_limit,
+            );
+            Ok(())
+        },
+    )
+}
@@ -0,0 +1,424 @@
+use noodles::bam::bai;
+use noodles::core::Region;
+use noodles::csi::BinningIndex;
+use noodles::sam::alignment::Record;
+use std::collections::VecDeque;
+use std::{iter, mem};
+
+use crate::error::MapTideError;
+use crate::options::PileupOptions;
+use crate::overlap::{MateOverlaps, OverlapMask, Ready};
+use crate::quality::{count_qualities, Qualities, QualityArr, QualityMap};
+use crate::{count_record, get_reader, intersects, BamReader, Coordinate, CoordinateMap, RefArr};
+
+/// A row of the pileup: reference name, position, insert position and base counts.
+pub type Row = (String, usize, usize, [usize; 6]);
+
+/// A row of the pileup, with its quality accumulators if they are collected.
+pub type QualityRow = (Row, Option<Qualities>);
+
+/// Iterator over the rows of a pileup, in sorted order.
+///
+/// Records are read and counted only as far as needed to finish the next row, so memory is bounded by the span of the reads covering a position.
+///
+/// Records in the BAM file must be sorted by coordinate.
+pub struct PileupStream {
+    reader: BamReader,
+
+    // Names and lengths of the references, in the order they appear in the BAM header
+    references: Vec<(String, usize)>,
+
+    // The references to iterate over, and the index of the current one
+    ref_ids: Vec<usize>,
+    current: usize,
+
+    region: Option<Region>,
+    options: PileupOptions,
+
+    // Counts over the current window of the reference, which starts after `offset`
+    ref_arr: RefArr,
+    offset: usize,
+    ins_map: CoordinateMap,
+
+    // Quality accumulators over the same window, if they are collected
+    qual_arr: QualityArr,
+    qual_map: QualityMap,
+
+    // The next position to return, and the position up to which all rows are finished
+    next_pos: usize,
+    limit: usize,
+
+    // Insertions to return after the last position
+    ins_rows: VecDeque<(Coordinate, [usize; 6], Option<Qualities>)>,
+
+    // The next record, if it belongs to a later reference
+    record: Option<Record>,
+
+    // First mates waiting for their mate, whose rows are not finished until they are counted
+    overlaps: MateOverlaps,
+}
+
+impl PileupStream {
+    /// Create a stream over all references of the BAM file located at `bam_path`.
+    pub fn all(bam_path: String, options: &PileupOptions) -> Result<Self, MapTideError> {
+        Self::new(bam_path, None, None, options)
+    }
+
+    /// Create a stream over `region` of the BAM file located at `bam_path`.
+    ///
+    /// If `bai_path` is `Some`, the index is used to seek to the first record intersecting `region`.
+    pub fn query(
+        bam_path: String,
+        bai_path: Option<String>,
+        region: Region,
+        options: &PileupOptions,
+    ) -> Result<Self, MapTideError> {
+        Self::new(bam_path, bai_path, Some(region), options)
+    }
+
+    /// Return an iterator over the rows of the pileup, each with its quality accumulators if `qualities` is set in the options.
+    pub fn with_qualities(mut self) -> impl Iterator<Item = Result<QualityRow, MapTideError>> {
+        iter::from_fn(move || self.next_row().transpose())
+    }
+
+    fn new(
+        bam_path: String,
+        bai_path: Option<String>,
+        region: Option<Region>,
+        options: &PileupOptions,
+    ) -> Result<Self, MapTideError> {
+        // Reader for iterating through records
+        let mut reader = get_reader(bam_path, options.threads())?;
+
+        // Reference sequence information
+        let ref_seqs = reader.read_reference_sequences()?;
+        let references: Vec<(String, usize)> = ref_seqs
+            .iter()
+            .map(|(name, reff)| (name.to_owned(), reff.length().get()))
+            .collect();
+
+        let ref_ids = match &region {
+            Some(reg) => {
+                let ref_id = ref_seqs
+                    .get_index_of(reg.name())
+                    .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+                if let Some(b_path) = bai_path {
+                    // Read the index file, and seek to the first chunk intersecting the region
+                    let index = bai::read(b_path)?;
+                    let chunks = index.query(ref_id, reg.interval())?;
+
+                    if let Some(start) = chunks.iter().map(|chunk| chunk.start()).min() {
+                        reader.seek(start)?;
+                    }
+                }
+
+                vec![ref_id]
+            }
+            None => (0..references.len()).collect(),
+        };
+
+        let mut stream = Self {
+            reader,
+            references,
+            ref_ids,
+            current: 0,
+            region,
+            options: options.clone(),
+            ref_arr: RefArr::new(),
+            offset: 0,
+            ins_map: CoordinateMap::new(),
+            qual_arr: QualityArr::new(),
+            qual_map: QualityMap::new(),
+            next_pos: 0,
+            limit: 0,
+            ins_rows: VecDeque::new(),
+            record: None,
+            overlaps: MateOverlaps::new(
+                options.overlaps(),
+                options.base_quality(),
+                options.trim_ends(),
+            ),
+        };
+
+        if let Some(&ref_id) = stream.ref_ids.first() {
+            let (_, start, _) = stream.bounds(ref_id)?;
+            stream.reset(start);
+        }
+
+        Ok(stream)
+    }
+
+    /// Return the name, start and end of the reference at `ref_id`, restricted to the region if there is one.
+    fn bounds(&self, ref_id: usize) -> Result<(&str, usize, usize), MapTideError> {
+        let (ref_name, ref_length) = self
+            .references
+            .get(ref_id)
+            .ok_or_else(|| MapTideError::IndexNotFound)?;
+
+        match &self.region {
+            Some(reg) => Ok((
+                ref_name,
+                reg.interval().start().map(|x| x.get()).unwrap_or(1),
+                reg.interval().end().map(|x| x.get()).unwrap_or(*ref_length),
+            )),
+            None => Ok((ref_name, 1, *ref_length)),
+        }
+    }
+
+    /// Clear the counts, ready to start a reference from `start`.
+    fn reset(&mut self, start: usize) {
+        self.ref_arr.clear();
+        self.ins_map.clear();
+        self.qual_arr.clear();
+        self.qual_map.clear();
+        self.offset = start - 1;
+        self.next_pos = start;
+        self.limit = 0;
+    }
+
+    /// Read the next record that passes the filters, or return `None` if there are no more.
+    fn read_record(&mut self) -> Result<Option<Record>, MapTideError> {
+        loop {
+            let mut record = Record::default();
+
+            if self.reader.read_record(&mut record)? == 0 {
+                return Ok(None);
+            }
+
+            // Records outside the region are skipped before filtering, as they may lack fields that the filters need
+            if let Some(reg) = &self.region {
+                // Unplaced records are sorted after every reference
+                let ref_id = match record.reference_sequence_id() {
+                    Some(x) => x,
+                    None => return Ok(None),
+                };
+
+                // Records are sorted, so none after the region can intersect it
+                if ref_id > self.ref_ids[0]
+                    || (ref_id == self.ref_ids[0]
+                        && reg.interval().end().map_or(false, |end| {
+                            record.alignment_start().map_or(false, |start| start > end)
+                        }))
+                {
+                    return Ok(None);
+                }
+
+                if ref_id != self.ref_ids[0] || !intersects(&record, reg).unwrap_or(false) {
+                    continue;
+                }
+            }
+
+            if !self.options.filter(&record)? {
+                continue;
+            }
+
+            return Ok(Some(record));
+        }
+    }
+
+    /// Count the next record on the reference at `ref_id`, and update the position up to which all rows are finished.
+    fn advance(&mut self, ref_id: usize, end: usize) -> Result<(), MapTideError> {
+        if self.record.is_none() {
+            self.record = self.read_record()?;
+        }
+
+        let record = match self.record.take() {
+            Some(x) => x,
+            None => {
+                // There are no more records, so every row is finished
+                let ready = self.overlaps.finish();
+                self.count_ready(ready, ref_id, end)?;
+                self.limit = usize::MAX;
+                return Ok(());
+            }
+        };
+
+        let record_ref_id = record
+            .reference_sequence_id()
+            .ok_or_else(|| MapTideError::ReferenceSequenceIDNotFound)?;
+
+        let record_start = record
+            .alignment_start()
+            .ok_or_else(|| MapTideError::AlignmentStartNotFound)?
+            .get();
+
+        if record_ref_id > ref_id {
+            // The record belongs to a later reference, so every row of this one is finished
+            let ready = self.overlaps.finish();
+            self.count_ready(ready, ref_id, end)?;
+            self.record = Some(record);
+            self.limit = usize::MAX;
+            return Ok(());
+        }
+
+        if record_ref_id < ref_id || record_start < self.limit {
+            return Err(MapTideError::UnsortedRecords);
+        }
+
+        let ready = self.overlaps.push(record)?;
+
+        // No later record can start before this one, and rows covered by a waiting mate are not finished
+        self.limit = self
+            .overlaps
+            .earliest_start()
+            .map_or(record_start, |x| x.min(record_start));
+
+        self.count_ready(ready, ref_id, end)
+    }
+
+    /// Count the `ready` records on the reference at `ref_id`.
+    fn count_ready(&mut self, ready: Ready, ref_id: usize, end: usize) -> Result<(), MapTideError> {
+        if ready.is_empty() {
+            return Ok(());
+        }
+
+        // Drop the returned positions from the window
+        let returned = (self.next_pos - self.offset - 1).min(self.ref_arr.len());
+        self.ref_arr.drain(..returned);
+        self.qual_arr.drain(..returned.min(self.qual_arr.len()));
+        self.offset += returned;
+
+        let (_, start, _) = self.bounds(ref_id)?;
+
+        for (record, mask) in ready {
+            self.count(&record, &mask, start, end)?;
+        }
+
+        Ok(())
+    }
+
+    /// Extend the window to cover `record`, and count it between `start` and `end`.
+    fn count(
+        &mut self,
+        record: &Record,
+        mask: &OverlapMask,
+        start: usize,
+        end: usize,
+    ) -> Result<(), MapTideError> {
+        let record_end = record
+            .alignment_end()
+            .ok_or_else(|| MapTideError::AlignmentEndNotFound)?
+            .get();
+
+        let window_len = record_end.min(end).saturating_sub(self.offset);
+        if self.ref_arr.len() < window_len {
+            self.ref_arr.resize(window_len, [0; 6]);
+        }
+
+        count_record(
+            &mut self.ref_arr,
+            self.offset,
+            &mut self.ins_map,
+            record,
+            mask,
+            self.options.base_quality(),
+            self.options.trim_ends(),
+            start,
+            end,
+        )?;
+
+        if self.options.qualities() {
+            if self.qual_arr.len() < window_len {
+                self.qual_arr.resize(window_len, Qualities::default());
+            }
+
+            count_qualities(
+                &mut self.qual_arr,
+                self.offset,
+                &mut self.qual_map,
+                record,
+                mask,
+                self.options.base_quality(),
+                self.options.trim_ends(),
+                self.options.end_distance(),
+                start,
+                end,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Move the insertions at `pos` from `ins_map` to `ins_rows`, in sorted order.
+    fn queue_insertions(&mut self, pos: usize) {
+        if self.ins_map.is_empty() {
+            return;
+        }
+
+        // Insertions are sorted, and those at earlier positions have already been queued
+        let later = self.ins_map.split_off(&Coordinate(pos + 1, 0));
+        let current = mem::replace(&mut self.ins_map, later);
+
+        let later_quals = self.qual_map.split_off(&Coordinate(pos + 1, 0));
+        let mut current_quals = mem::replace(&mut self.qual_map, later_quals);
+
+        let collect_qualities = self.options.qualities();
+        self.ins_rows
+            .extend(current.into_iter().map(|(coord, row)| {
+                let quals =
+                    collect_qualities.then(|| current_quals.remove(&coord).unwrap_or_default());
+                (coord, row, quals)
+            }));
+    }
+
+    /// Return the next row of the pileup and its quality accumulators, or `None` if there are no more.
+    fn next_row(&mut self) -> Result<Option<QualityRow>, MapTideError> {
+        loop {
+            let ref_id = match self.ref_ids.get(self.current) {
+                Some(&x) => x,
+                None => return Ok(None),
+            };
+
+            let (ref_name, _, end) = self.bounds(ref_id)?;
+            let ref_name = ref_name.to_owned();
+
+            // Insertions follow the position they were queued at
+            if let Some((Coordinate(pos, ins_pos), row, quals)) = self.ins_rows.pop_front() {
+                return Ok(Some(((ref_name, pos, ins_pos, row), quals)));
+            }
+
+            if self.next_pos > end {
+                // Move on to the next reference
+                self.current += 1;
+
+                if let Some(&next_id) = self.ref_ids.get(self.current) {
+                    let (_, start, _) = self.bounds(next_id)?;
+                    self.reset(start);
+                }
+                continue;
+            }
+
+            if self.next_pos < self.limit {
+                let pos = self.next_pos;
+                let row = self
+                    .ref_arr
+                    .get(pos - self.offset - 1)
+                    .copied()
+                    .unwrap_or([0; 6]);
+
+                let quals = self.options.qualities().then(|| {
+                    self.qual_arr
+                        .get(pos - self.offset - 1)
+                        .copied()
+                        .unwrap_or_default()
+                });
+
+                self.queue_insertions(pos);
+                self.next_pos += 1;
+
+                return Ok(Some(((ref_name, pos, 0, row), quals)));
+            }
+
+            self.advance(ref_id, end)?;
+        }
+    }
+}
+
+impl Iterator for PileupStream {
+    type Item = Result<Row, MapTideError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_row().map(|x| x.map(|(row, _)| row)).transpose()
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+use std::io::{BufWriter, Write};
+
+use crate::depth::depth;
+use crate::error::MapTideError;
+use crate::tsv::format_rounded;
+use crate::{get_writer, Pileup, DELETION_INDEX, N_INDEX};
+
+/// Depths that the breadth of coverage is reported at.
+pub const BREADTH_DEPTHS: [usize; 3] = [1, 10, 20];
+
+/// Coverage metrics over the counted positions of a reference.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub ref_name: String,
+
+    /// First counted position (1-based).
+    pub start: usize,
+
+    /// Last counted position (1-based, inclusive).
+    pub end: usize,
+
+    pub mean_depth: f64,
+    pub median_depth: f64,
+
+    /// Fraction of positions with a depth of at least each of `BREADTH_DEPTHS`.
+    pub breadth: [f64; 3],
+
+    /// Fraction of all counted bases that are N.
+    pub n_fraction: f64,
+
+    /// Fraction of all counted bases that are deletions.
+    pub deletion_fraction: f64,
+}
+
+/// Return the median of the depths in `histogram`, which maps each depth to its number of positions.
+fn median(histogram: &BTreeMap<usize, usize>, n: usize) -> f64 {
+    if n == 0 {
+        return 0.0;
+    }
+
+    // Indices of the middle depths, which are equal when `n` is odd
+    let lower = (n - 1) / 2;
+    let upper = n / 2;
+
+    let mut lower_depth = None;
+    let mut seen = 0;
+
+    for (d, count) in histogram.iter() {
+        seen += count;
+
+        if lower_depth.is_none() && seen > lower {
+            lower_depth = Some(*d);
+        }
+
+        if seen > upper {
+            return (lower_depth.unwrap_or(*d) + d) as f64 / 2.0;
+        }
+    }
+
+    0.0
+}
+
+/// Return `x` as a fraction of `total`, or zero if `total` is zero.
+fn fraction(x: usize, total: usize) -> f64 {
+    if total > 0 {
+        x as f64 / total as f64
+    } else {
+        0.0
+    }
+}
+
+/// Summarise the coverage of each reference in `pileup`, over the positions that were counted.
+///
+/// Depths are the total count at each reference position, including deletions and Ns. Insertions are not included.
+pub fn summarise(pileup: &Pileup) -> Result<Vec<Summary>, MapTideError> {
+    let mut summaries = Vec::new();
+
+    for ref_name in pileup.ref_names.iter() {
+        let (ref_arr, offset) = pileup
+            .ref_arrs
+            .get(ref_name)
+            .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+        let mut histogram = BTreeMap::new();
+        let mut covered = [0; 3];
+        let mut totals = [0; 6];
+
+        for row in ref_arr.rows() {
+            let row_depth = depth(&row, None);
+            *histogram.entry(row_depth).or_insert(0) += 1;
+
+            for (n, min_depth) in covered.iter_mut().zip(BREADTH_DEPTHS.iter()) {
+                if row_depth >= *min_depth {
+                    *n += 1;
+                }
+            }
+
+            for (total, count) in totals.iter_mut().zip(row.iter()) {
+                *total += count;
+            }
+        }
+
+        let n = ref_arr.len();
+        let total: usize = totals.iter().sum();
+
+        summaries.push(Summary {
+            ref_name: ref_name.to_owned(),
+            start: offset + 1,
+            end: offset + n,
+            mean_depth: fraction(total, n),
+            median_depth: median(&histogram, n),
+            breadth: covered.map(|x| fraction(x, n)),
+            n_fraction: fraction(totals[N_INDEX], total),
+            deletion_fraction: fraction(totals[DELETION_INDEX], total),
+        });
+    }
+
+    Ok(summaries)
+}
+
+/// Largest depth allowed for the final bucket of a histogram.
+const MAX_HISTOGRAM_CAP: usize = 10_000_000;
+
+/// Count the reference positions of each reference in `pileup` at each depth, from zero up to `cap`.
+///
+/// The final bucket of each histogram counts all positions with a depth of at least `cap`, which can be at most ten million. Depths are the total count at each reference position, and insertions are not included.
+pub fn depth_histograms(
+    pileup: &Pileup,
+    cap: usize,
+) -> Result<Vec<(String, Vec<usize>)>, MapTideError> {
+    if cap > MAX_HISTOGRAM_CAP {
+        return Err(MapTideError::InvalidOption("histogram_cap".to_string()));
+    }
+
+    let mut histograms = Vec::new();
+
+    for ref_name in pileup.ref_names.iter() {
+        let (ref_arr, _) = pileup
+            .ref_arrs
+            .get(ref_name)
+            .ok_or_else(|| MapTideError::KeyNotFound)?;
+
+        let mut histogram = vec![0; cap + 1];
+        for row in ref_arr.rows() {
+            histogram[depth(&row, None).min(cap)] += 1;
+        }
+
+        histograms.push((ref_name.to_owned(), histogram));
+    }
+
+    Ok(histograms)
+}
+
+/// Write `summaries` as a tab-separated table.
+fn write_table<W: Write>(
+    writer: &mut W,
+    summaries: &[Summary],
+    decimals: usize,
+) -> Result<(), MapTideError> {
+    let mut header = vec![
+        "chrom".to_string(),
+        "start".to_string(),
+        "end".to_string(),
+        "mean_depth".to_string(),
+        "median_depth".to_string(),
+    ];
+    header.extend(BREADTH_DEPTHS.iter().map(|d| format!("breadth_{}x", d)));
+    header.push("n_fraction".to_string());
+    header.push("deletion_fraction".to_string());
+
+    writeln!(writer, "{}", header.join("\t"))?;
+
+    for summary in summaries.iter() {
+        let mut line = vec![
+            summary.ref_name.clone(),
+            summary.start.to_string(),
+            summary.end.to_string(),
+            format_rounded(summary.mean_depth, decimals),
+            format_rounded(summary.median_depth, decimals),
+        ];
+        line.extend(summary.breadth.iter().map(|x| format_rounded(*x, decimals)));
+        line.push(format_rounded(summary.n_fraction, decimals));
+        line.push(format_rounded(summary.deletion_fraction, decimals));
+
+        writeln!(writer, "{}", line.join("\t"))?;
+    }
+
+    Ok(())
+}
+
+/// Write a table of the coverage of each reference in `pileup` to `output_path`, or to stdout if `output_path` is `None`.
+///
+/// Metrics are written with `decimals` decimal places.
+pub fn write_summary(
+    pileup: &Pileup,
+    output_path: Option<String>,
+    decimals: usize,
+) -> Result<(), MapTideError> {
+    let summaries = summarise(pileup)?;
+    let mut writer = BufWriter::new(get_writer(output_path)?);
+    write_table(&mut writer, &summaries, decimals)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write the depth histogram of each reference in `pileup` to `output_path`, or to stdout if `output_path` is `None`.
+///
+/// Each line is a reference, a depth, and the number of positions at that depth. The final depth of each reference is written as `{cap}+`.
+pub fn write_histogram(
+    pileup: &Pileup,
+    output_path: Option<String>,
+    cap: usize,
+) -> Result<(), MapTideError> {
+    let histograms = depth_histograms(pileup, cap)?;
+    let mut writer = BufWriter::new(get_writer(output_path)?);
+
+    writeln!(writer, "chrom\tdepth\tpositions")?;
+
+    for (ref_name, histogram) in histograms.iter() {
+        for (d, count) in histogram.iter().enumerate() {
+            if d == cap {
+                writeln!(writer, "{}\t{}+\t{}", ref_name, d, count)?;
+            } else {
+                writeln!(writer, "{}\t{}\t{}", ref_name, d, count)?;
+            }
+        }
+    }
+
+    writer.flush()?;
+    Ok(())
+}
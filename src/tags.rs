@@ -0,0 +1,249 @@
+use noodles::sam::alignment::Record;
+use noodles::sam::record::data::field::{Tag, Value};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt::{self, Display};
+use std::fs;
+use std::str::FromStr;
+
+use crate::error::MapTideError;
+
+/// Comparison operators, longest first so that `<=` is not read as `<`.
+const OPERATORS: [(&str, Comparison); 6] = [
+    ("==", Comparison::Eq),
+    ("!=", Comparison::Ne),
+    ("<=", Comparison::Le),
+    (">=", Comparison::Ge),
+    ("<", Comparison::Lt),
+    (">", Comparison::Gt),
+];
+
+/// A comparison between the value of a tag and the value of a filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    /// Check that `ordering`, of the value of a tag against the value of the filter, satisfies the comparison.
+    fn holds(&self, ordering: Ordering) -> bool {
+        match self {
+            Comparison::Eq => ordering == Ordering::Equal,
+            Comparison::Ne => ordering != Ordering::Equal,
+            Comparison::Lt => ordering == Ordering::Less,
+            Comparison::Le => ordering != Ordering::Greater,
+            Comparison::Gt => ordering == Ordering::Greater,
+            Comparison::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+/// The test a tag filter applies to the value of its tag.
+#[derive(Debug, Clone, PartialEq)]
+enum Test {
+    /// The tag is present.
+    Exists,
+
+    /// The tag is absent.
+    Missing,
+
+    /// The value of the tag compares to the given value, as a number if both are numbers.
+    Compare(Comparison, String, Option<f64>),
+
+    /// The value of the tag is one of the given values.
+    In(HashSet<String>),
+
+    /// The value of the tag is none of the given values.
+    NotIn(HashSet<String>),
+}
+
+/// A filter on the tags of a record, parsed from an expression.
+///
+/// Expressions are one of:
+/// - `TAG`: the record has the tag.
+/// - `!TAG`: the record does not have the tag.
+/// - `TAG OP VALUE`, where `OP` is one of `==`, `!=`, `<`, `<=`, `>` or `>=`: the value of the tag compares to `VALUE`. Numeric tags are compared as numbers, and other tags only support `==` and `!=`.
+/// - `TAG in VALUES` or `TAG !in VALUES`: the value of the tag is (or is not) one of `VALUES`, which are separated by commas, or read one per line from a file if written as `@PATH`.
+///
+/// Records without the tag fail every comparison and membership test.
+///
+/// Filters are serialised with the values of `@PATH` written inline, so that loading them does not read the file again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TagFilter {
+    expression: String,
+    tag: Tag,
+    test: Test,
+}
+
+impl TagFilter {
+    /// Check that `record` passes the filter.
+    pub fn matches(&self, record: &Record) -> bool {
+        let value = record.data().get(self.tag).map(|field| field.value());
+
+        match (&self.test, value) {
+            (Test::Exists, value) => value.is_some(),
+            (Test::Missing, value) => value.is_none(),
+            (_, None) => false,
+            (Test::Compare(comparison, text, number), Some(value)) => {
+                match (as_number(value), number) {
+                    (Some(x), Some(y)) => x
+                        .partial_cmp(y)
+                        .map_or(false, |ordering| comparison.holds(ordering)),
+                    _ => match (comparison, as_text(value)) {
+                        (Comparison::Eq, Some(x)) => &x == text,
+                        (Comparison::Ne, Some(x)) => &x != text,
+                        _ => false,
+                    },
+                }
+            }
+            (Test::In(values), Some(value)) => {
+                as_text(value).map_or(false, |x| values.contains(&x))
+            }
+            (Test::NotIn(values), Some(value)) => {
+                as_text(value).map_or(false, |x| !values.contains(&x))
+            }
+        }
+    }
+
+    /// The expression the filter was parsed from.
+    pub fn expression(&self) -> &str {
+        &self.expression
+    }
+}
+
+/// Return the value of a numeric tag as a number.
+fn as_number(value: &Value) -> Option<f64> {
+    value
+        .as_int()
+        .map(|x| x as f64)
+        .or_else(|| value.as_float().map(f64::from))
+}
+
+/// Return the value of a tag as text, for comparing against the values of a filter.
+fn as_text(value: &Value) -> Option<String> {
+    match value {
+        Value::Char(c) => Some(c.to_string()),
+        Value::String(s) | Value::Hex(s) => Some(s.to_owned()),
+        _ => value.as_int().map(|x| x.to_string()),
+    }
+}
+
+/// Parse `values`, which are separated by commas, or read one per line from a file if written as `@PATH` and `read_files` is `true`.
+///
+/// Values read from a file must not contain commas, and there must be at least one, so that they can be written inline.
+fn parse_values(
+    expression: &str,
+    values: &str,
+    read_files: bool,
+) -> Result<HashSet<String>, MapTideError> {
+    let values: HashSet<String> = match values.strip_prefix('@').filter(|_| read_files) {
+        Some(path) => fs::read_to_string(path)?
+            .lines()
+            .map(|x| x.trim().to_owned())
+            .filter(|x| !x.is_empty())
+            .collect(),
+        None => values.split(',').map(|x| x.trim().to_owned()).collect(),
+    };
+
+    if values.is_empty() || values.iter().any(|x| x.contains(',')) {
+        return Err(MapTideError::InvalidTagFilter(expression.to_owned()));
+    }
+
+    Ok(values)
+}
+
+impl TagFilter {
+    /// Parse a filter from `s`, reading the values of `@PATH` from the file if `read_files` is `true`.
+    fn parse(s: &str, read_files: bool) -> Result<Self, MapTideError> {
+        let expression = s.trim().to_owned();
+        let invalid = || MapTideError::InvalidTagFilter(expression.clone());
+        let parse_tag = |tag: &str| tag.trim().parse::<Tag>().map_err(|_| invalid());
+
+        let (tag, test) = if let Some((tag, values)) = expression.split_once(" !in ") {
+            let values = parse_values(&expression, values.trim(), read_files)?;
+            (parse_tag(tag)?, Test::NotIn(values))
+        } else if let Some((tag, values)) = expression.split_once(" in ") {
+            let values = parse_values(&expression, values.trim(), read_files)?;
+            (parse_tag(tag)?, Test::In(values))
+        } else if let Some(i) = expression.find(|c| "=!<>".contains(c)).filter(|&i| i > 0) {
+            let (tag, rest) = expression.split_at(i);
+            let (operator, comparison) = OPERATORS
+                .iter()
+                .find(|(operator, _)| rest.starts_with(operator))
+                .ok_or_else(invalid)?;
+
+            let value = rest[operator.len()..].trim();
+            if value.is_empty() {
+                return Err(invalid());
+            }
+
+            (
+                parse_tag(tag)?,
+                Test::Compare(*comparison, value.to_owned(), value.parse().ok()),
+            )
+        } else if let Some(tag) = expression.strip_prefix('!') {
+            (parse_tag(tag)?, Test::Missing)
+        } else {
+            (parse_tag(&expression)?, Test::Exists)
+        };
+
+        Ok(Self {
+            expression,
+            tag,
+            test,
+        })
+    }
+}
+
+impl FromStr for TagFilter {
+    type Err = MapTideError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s, true)
+    }
+}
+
+// Serialised filters have their values inline, so files are not read when they are loaded
+impl TryFrom<String> for TagFilter {
+    type Error = MapTideError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::parse(&s, false)
+    }
+}
+
+impl From<TagFilter> for String {
+    fn from(filter: TagFilter) -> Self {
+        let (operator, values) = match &filter.test {
+            Test::In(values) => ("in", values),
+            Test::NotIn(values) => ("!in", values),
+            _ => return filter.expression,
+        };
+
+        let mut values: Vec<&str> = values.iter().map(String::as_str).collect();
+        values.sort_unstable();
+        format!("{} {} {}", filter.tag, operator, values.join(","))
+    }
+}
+
+impl Display for TagFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+// Filters are equal if they apply the same test, whether their values were written inline or read from a file
+impl PartialEq for TagFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.tag == other.tag && self.test == other.test
+    }
+}
+
+impl Eq for TagFilter {}
@@ -0,0 +1,241 @@
+use noodles::bgzf;
+use std::io::{BufWriter, Write};
+
+use crate::error::MapTideError;
+use crate::options::PileupOptions;
+use crate::quality::Qualities;
+use crate::stream::QualityRow;
+use crate::{get_writer, BASES, DELETION_INDEX};
+
+/// Calculate the entropy of `probabilities`, optionally normalised by the maximum possible entropy.
+fn entropy(probabilities: &[f64], normalised: bool) -> f64 {
+    let ent = probabilities
+        .iter()
+        .map(|&x| if x != 0.0 { -(x * x.log2()) } else { 0.0 })
+        .fold(0.0, |acc, x| acc + x);
+
+    if normalised {
+        ent / (probabilities.len() as f64).log2()
+    } else {
+        ent
+    }
+}
+
+/// Convert `counts` into probabilities, which are all zero if there are no counts.
+fn probabilities(counts: &[usize]) -> Vec<f64> {
+    let coverage: usize = counts.iter().sum();
+
+    counts
+        .iter()
+        .map(|&count| {
+            if coverage > 0 {
+                count as f64 / coverage as f64
+            } else {
+                0.0
+            }
+        })
+        .collect()
+}
+
+/// Format `x` rounded to `decimals` places, in the same way as Python's `repr(round(x, decimals))`.
+///
+/// Rounding is done on the exact value of `x`, with ties rounded to even, and very small or large values are written with an exponent.
+pub(crate) fn format_rounded(x: f64, decimals: usize) -> String {
+    // Formatting with a precision rounds the exact value, with ties to even
+    let rounded: f64 = format!("{:.*}", decimals, x).parse().unwrap_or(x);
+
+    let magnitude = rounded.abs();
+    if magnitude != 0.0 && !(1e-4..1e16).contains(&magnitude) {
+        let formatted = format!("{:e}", rounded);
+        if let Some((mantissa, exponent)) = formatted.split_once('e') {
+            let (sign, digits) = match exponent.strip_prefix('-') {
+                Some(digits) => ('-', digits),
+                None => ('+', exponent),
+            };
+            return format!("{}e{}{:0>2}", mantissa, sign, digits);
+        }
+    }
+
+    let formatted = rounded.to_string();
+    if formatted.contains('.') {
+        formatted
+    } else {
+        formatted + ".0"
+    }
+}
+
+/// Return the percentage of each base, the entropy and the secondary entropy of `row`.
+///
+/// The secondary entropy is calculated over all bases except the most frequent.
+fn row_stats(row: &[usize; 6], decimals: usize) -> Vec<String> {
+    let probs = probabilities(row);
+    let ent = entropy(&probs, true);
+
+    // Remove the first occurrence of the maximum count
+    let mut secondary_counts = row.to_vec();
+    let max_count = row.iter().max().copied().unwrap_or(0);
+    if let Some(max_index) = row.iter().position(|&count| count == max_count) {
+        secondary_counts.remove(max_index);
+    }
+    let secondary_ent = entropy(&probabilities(&secondary_counts), true);
+
+    probs
+        .iter()
+        .map(|p| 100.0 * p)
+        .chain([ent, secondary_ent])
+        .map(|x| format_rounded(x, decimals))
+        .collect()
+}
+
+/// Written in place of a statistic of a position without the bases to calculate it.
+const MISSING: &str = "NA";
+
+/// Return the mean and standard deviation of the base quality of each base (other than deletions), and the mean mapping quality.
+///
+/// These are followed by the mean distance of each base (including deletions) from the nearer aligned end of its reads, and the number of each base near a read end.
+fn quality_stats(row: &[usize; 6], quals: &Qualities, decimals: usize) -> Vec<String> {
+    let format = |x: Option<f64>| x.map_or(MISSING.to_string(), |x| format_rounded(x, decimals));
+
+    // Deletions have no base quality
+    let base_indices = (0..BASES.len()).filter(|&i| i != DELETION_INDEX);
+
+    base_indices
+        .clone()
+        .map(|i| format(quals.mean_base_quality(i)))
+        .chain(base_indices.map(|i| format(quals.sd_base_quality(i))))
+        .chain([format(quals.mean_mapping_quality())])
+        .chain((0..BASES.len()).map(|i| format(quals.mean_end_distance(i, row[i]))))
+        .chain(quals.near_end_counts.iter().map(|n| n.to_string()))
+        .collect()
+}
+
+/// Write the column names of the table, with or without the `stats` columns, and the quality columns if `qualities` is `true`.
+fn write_header<W: Write>(
+    writer: &mut W,
+    stats: bool,
+    qualities: bool,
+) -> Result<(), MapTideError> {
+    let mut columns = vec![
+        "chrom".to_string(),
+        "pos".to_string(),
+        "ins".to_string(),
+        "cov".to_string(),
+    ];
+    columns.extend(BASES.iter().map(|base| base.to_lowercase()));
+
+    if stats {
+        columns.extend(
+            BASES
+                .iter()
+                .map(|base| format!("pc_{}", base.to_lowercase())),
+        );
+        columns.push("entropy".to_string());
+        columns.push("secondary_entropy".to_string());
+
+        if qualities {
+            let bases: Vec<String> = BASES
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| i != DELETION_INDEX)
+                .map(|(_, base)| base.to_lowercase())
+                .collect();
+
+            columns.extend(bases.iter().map(|base| format!("mean_bq_{}", base)));
+            columns.extend(bases.iter().map(|base| format!("sd_bq_{}", base)));
+            columns.push("mean_mq".to_string());
+            columns.extend(
+                BASES
+                    .iter()
+                    .map(|base| format!("mean_end_dist_{}", base.to_lowercase())),
+            );
+            columns.extend(
+                BASES
+                    .iter()
+                    .map(|base| format!("near_end_{}", base.to_lowercase())),
+            );
+        }
+    }
+
+    writeln!(writer, "{}", columns.join("\t"))?;
+    Ok(())
+}
+
+/// Write a line of the table for each row in `rows`.
+fn write_rows<W, I>(
+    writer: &mut W,
+    rows: I,
+    stats: bool,
+    qualities: bool,
+    decimals: usize,
+) -> Result<(), MapTideError>
+where
+    W: Write,
+    I: Iterator<Item = Result<QualityRow, MapTideError>>,
+{
+    write_header(writer, stats, qualities)?;
+
+    for result in rows {
+        let ((ref_name, pos, ins_pos, row), quals) = result?;
+
+        write!(
+            writer,
+            "{}\t{}\t{}\t{}",
+            ref_name,
+            pos,
+            ins_pos,
+            row.iter().sum::<usize>()
+        )?;
+
+        for count in row.iter() {
+            write!(writer, "\t{}", count)?;
+        }
+
+        if stats {
+            for stat in row_stats(&row, decimals) {
+                write!(writer, "\t{}", stat)?;
+            }
+
+            if qualities {
+                let quals = quals.unwrap_or_default();
+                for stat in quality_stats(&row, &quals, decimals) {
+                    write!(writer, "\t{}", stat)?;
+                }
+            }
+        }
+
+        writeln!(writer)?;
+    }
+
+    Ok(())
+}
+
+/// Write `rows` as a tab-separated table to `output_path`, or to stdout if `output_path` is `None`.
+///
+/// Each line is written as soon as its row is available.
+///
+/// Statistics are written if `stats` is set in `options`, including the mean and standard deviation of the base quality of each base, the mean mapping quality, and the distance of each base from read ends if `qualities` is also set. If `bgzip` is set, the output is BGZF-compressed, and can be indexed with `tabix -s 1 -b 2 -e 2 -S 1`.
+pub fn write_tsv<I>(
+    rows: I,
+    output_path: Option<String>,
+    options: &PileupOptions,
+) -> Result<(), MapTideError>
+where
+    I: Iterator<Item = Result<QualityRow, MapTideError>>,
+{
+    let output = get_writer(output_path)?;
+    let stats = options.stats();
+    let qualities = options.qualities();
+    let decimals = options.decimals();
+
+    if options.bgzip() {
+        let mut writer = bgzf::Writer::new(output);
+        write_rows(&mut writer, rows, stats, qualities, decimals)?;
+        writer.finish()?;
+    } else {
+        let mut writer = BufWriter::new(output);
+        write_rows(&mut writer, rows, stats, qualities, decimals)?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}